@@ -1,6 +1,7 @@
 use eframe::egui;
 use memoize::memoize;
 use std::fmt::Write;
+use std::sync::Arc;
 
 #[memoize]
 fn tex_to_svg(tex: String) -> Vec<u8> {
@@ -8,12 +9,13 @@ fn tex_to_svg(tex: String) -> Vec<u8> {
 }
 
 // egui supports svg, but scaling is blurry for some reason,
-// so manually scale and rasterize to png
-fn tex_to_image(ppp: f32, tex: String) -> egui::Image<'static> {
-    let uri = format!("bytes://{tex}.png");
+// so manually scale and rasterize to png.
+// Keyed on the bits of the points per pixel, as floats aren't hashable
+#[memoize]
+fn tex_to_png(tex: String, ppp_bits: u32) -> Arc<[u8]> {
     let svg = tex_to_svg(tex);
     let svg = resvg::usvg::Tree::from_data(&svg, &Default::default()).unwrap();
-    let scale = 2.0 * ppp;
+    let scale = 2.0 * f32::from_bits(ppp_bits);
     let size = svg.size().to_int_size().scale_by(scale).unwrap();
     let mut pixmap = resvg::tiny_skia::Pixmap::new(size.width(), size.height()).unwrap();
     resvg::render(
@@ -21,22 +23,24 @@ fn tex_to_image(ppp: f32, tex: String) -> egui::Image<'static> {
         resvg::tiny_skia::Transform::from_scale(scale, scale),
         &mut pixmap.as_mut(),
     );
-    let png = pixmap.encode_png().unwrap();
+    pixmap.encode_png().unwrap().into()
+}
+
+fn tex_to_image(ppp: f32, tex: String) -> egui::Image<'static> {
+    let uri = format!("bytes://{tex}.png");
+    let png = tex_to_png(tex, ppp.to_bits());
     egui::Image::from_bytes(uri, png).fit_to_original_size(1.0 / ppp)
 }
 
-fn render_inference(
-    ppp: f32,
-    mm: &lib::Context,
-    stmt_addr: lib::StatementAddress,
-) -> egui::Image<'static> {
-    let stmt_name = mm.label(stmt_addr);
-    let (hyps, conclusion) = mm.render_inference(stmt_addr);
+/// TeX of the inference of a step, its label above hypotheses over conclusion
+fn inference_tex(mm: &lib::Context, lemmas: &[lib::Lemma], step: lib::Step) -> String {
+    let stmt_name = mm.step_label(step, lemmas);
+    let (hyps, conclusion) = mm.render_step_inference(step, lemmas);
     let hyps_tex = hyps.into_iter().fold(String::new(), |mut out, hyp| {
         write!(out, "\\displaystyle {hyp} \\\\ ").unwrap();
         out
     });
-    let tex = format!(
+    format!(
         "
         \\boxed{{
             \\texttt{{ {stmt_name} }}
@@ -49,18 +53,324 @@ fn render_inference(
             }}
         }}
         "
+    )
+}
+
+/// Image of the inference of a step, rendered once per step
+fn render_inference(
+    ppp: f32,
+    mm: &lib::Context,
+    lemmas: &[lib::Lemma],
+    inferences: &mut lib::StepCache<String>,
+    step: lib::Step,
+) -> egui::Image<'static> {
+    let tex = inferences.get(step, || inference_tex(mm, lemmas, step));
+    tex_to_image(ppp, tex.clone())
+}
+
+/// Draw the description of a statement,
+/// returning the label of the level clicked among its cross-references if any
+fn description_ui(ui: &mut egui::Ui, ppp: f32, parts: &[lib::CommentPart]) -> Option<String> {
+    let mut clicked = None;
+    ui.horizontal_wrapped(|ui| {
+        ui.spacing_mut().item_spacing.x = 0.0;
+        for part in parts {
+            match part {
+                lib::CommentPart::Text(text) => {
+                    ui.label(text.as_str());
+                }
+                lib::CommentPart::ParagraphBreak => ui.end_row(),
+                lib::CommentPart::Italic(text) => {
+                    ui.label(egui::RichText::new(text).italics());
+                }
+                lib::CommentPart::Math { rendered, .. } => {
+                    ui.add(tex_to_image(ppp, rendered.clone()));
+                }
+                lib::CommentPart::Label {
+                    label,
+                    is_level: true,
+                } => {
+                    if ui.link(label.as_str()).clicked() {
+                        clicked = Some(label.clone());
+                    }
+                }
+                lib::CommentPart::Label { label, .. } => {
+                    ui.monospace(label.as_str());
+                }
+                lib::CommentPart::Url(url) => {
+                    ui.hyperlink(url);
+                }
+                lib::CommentPart::Citation(tag) => {
+                    ui.label(format!("[{tag}]"));
+                }
+            }
+        }
+    });
+    clicked
+}
+
+/// Draw a button per step, each enabled or not,
+/// returning the index of the enabled button clicked if any
+fn dep_buttons(
+    ui: &mut egui::Ui,
+    ppp: f32,
+    mm: &lib::Context,
+    lemmas: &[lib::Lemma],
+    inferences: &mut lib::StepCache<String>,
+    buttons: impl IntoIterator<Item = (lib::Step, bool)>,
+) -> Option<usize> {
+    let mut clicked = None;
+    for (i, (step, enabled)) in buttons.into_iter().enumerate() {
+        let image = render_inference(ppp, mm, lemmas, inferences, step);
+        let fill = if enabled {
+            egui::Color32::LIGHT_GRAY
+        } else {
+            egui::Color32::GRAY
+        };
+        let mut response = ui.add(egui::ImageButton::new(image.bg_fill(fill)));
+        if let lib::Step::Stmt(addr) = step {
+            response = response.on_hover_ui(|ui| {
+                description_ui(ui, ppp, &mm.description(addr));
+            });
+        }
+        if enabled && response.clicked() {
+            clicked = Some(i);
+        }
+    }
+    clicked
+}
+
+/// Draw levels in layers by how deep they are in the graph,
+/// returning the label of the level clicked if any
+fn level_map_ui(
+    ui: &mut egui::Ui,
+    graph: &lib::DepGraph,
+    current: lib::StatementAddress,
+    zoom: f32,
+) -> Option<String> {
+    let positions = graph.grid_positions();
+    let node_size = egui::vec2(100.0, 24.0) * zoom;
+    let spacing = egui::vec2(140.0, 36.0) * zoom;
+    let num_columns = positions.iter().map(|&(column, _)| column + 1).max();
+    let num_rows = positions.iter().map(|&(_, row)| row + 1).max();
+    let size = egui::vec2(
+        num_columns.unwrap_or(0) as f32 * spacing.x,
+        num_rows.unwrap_or(0) as f32 * spacing.y,
     );
-    tex_to_image(ppp, tex)
+    let mut clicked = None;
+    egui::ScrollArea::both()
+        .id_salt("level map")
+        .max_height(300.0)
+        .show(ui, |ui| {
+            let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+            let origin = response.rect.min;
+            let rect_of = |i: usize| {
+                let (column, row) = positions[i];
+                let offset = egui::vec2(column as f32 * spacing.x, row as f32 * spacing.y);
+                egui::Rect::from_min_size(origin + offset, node_size)
+            };
+            for edge in &graph.edges {
+                painter.line_segment(
+                    [
+                        rect_of(edge.from).right_center(),
+                        rect_of(edge.to).left_center(),
+                    ],
+                    egui::Stroke::new(1.0, egui::Color32::GRAY),
+                );
+            }
+            for (i, node) in graph.nodes.iter().enumerate() {
+                let rect = rect_of(i);
+                let fill = match graph.status(i) {
+                    lib::NodeStatus::Solved => egui::Color32::LIGHT_GREEN,
+                    lib::NodeStatus::Available => egui::Color32::LIGHT_YELLOW,
+                    lib::NodeStatus::Locked => egui::Color32::LIGHT_GRAY,
+                };
+                let stroke_width = if node.addr == current { 3.0 } else { 1.0 };
+                painter.rect(
+                    rect,
+                    4.0 * zoom,
+                    fill,
+                    egui::Stroke::new(stroke_width, egui::Color32::DARK_GRAY),
+                );
+                painter.text(
+                    rect.center(),
+                    egui::Align2::CENTER_CENTER,
+                    &node.label,
+                    egui::FontId::proportional(12.0 * zoom),
+                    egui::Color32::BLACK,
+                );
+                let id = ui.id().with(("level map", i));
+                if ui.interact(rect, id, egui::Sense::click()).clicked() {
+                    clicked = Some(node.label.clone());
+                }
+            }
+        });
+    clicked
+}
+
+fn truth_table_ui(ui: &mut egui::Ui, id_salt: &str, table: Option<lib::TruthTable>) {
+    // Larger tables are summarized only
+    const MAX_ROWS: usize = 64;
+    let Some(table) = table else {
+        ui.label("Not purely propositional");
+        return;
+    };
+    let value_name = |value: bool| if value { "T" } else { "F" };
+    match table.falsifying_assignment() {
+        None => ui.label("Tautology"),
+        Some(assignment) => {
+            let assignment = table
+                .vars
+                .iter()
+                .zip(assignment)
+                .map(|(var, &value)| format!("{var} = {}", value_name(value)))
+                .collect::<Vec<_>>();
+            ui.label(format!(
+                "Not a tautology, false when {}",
+                assignment.join(", ")
+            ))
+        }
+    };
+    if table.rows.len() <= MAX_ROWS {
+        egui::Grid::new(id_salt).striped(true).show(ui, |ui| {
+            for var in &table.vars {
+                ui.label(var.as_str());
+            }
+            ui.label("");
+            ui.end_row();
+            for (assignment, value) in &table.rows {
+                for &var_value in assignment {
+                    ui.label(value_name(var_value));
+                }
+                ui.strong(value_name(*value));
+                ui.end_row();
+            }
+        });
+    }
+}
+
+/// Apply an action, recording it into the macro being recorded if any
+fn act(
+    mm: &lib::Context,
+    lemmas: &[lib::Lemma],
+    state: &mut lib::State,
+    recording: &mut Option<lib::Macro>,
+    action: lib::Action,
+) {
+    if let Some(next_state) = state.apply(mm, lemmas, action) {
+        if let Some(mac) = recording {
+            mac.record(mm, lemmas, state, action);
+        }
+        *state = next_state;
+    }
+}
+
+/// Progress is kept as JSON next to the executable, as the web frontend keeps it in local storage
+fn progress_path() -> Option<std::path::PathBuf> {
+    Some(
+        std::env::current_exe()
+            .ok()?
+            .with_file_name("progress.json"),
+    )
+}
+
+fn load_progress() -> lib::Progress {
+    progress_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| lib::Progress::from_json(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save_progress(progress: &lib::Progress) {
+    let Some(path) = progress_path() else {
+        return;
+    };
+    if let Err(error) = std::fs::write(&path, progress.to_json()) {
+        eprintln!("cannot save the progress to {}: {error}", path.display());
+    }
+}
+
+/// Context of a level pack, with its database next to the manifest
+fn load_level_pack(manifest_path: &str) -> lib::Context {
+    let text = std::fs::read_to_string(manifest_path).expect("cannot read the manifest");
+    let manifest = lib::Manifest::from_json(&text).expect("invalid manifest");
+    let database_path = std::path::Path::new(manifest_path)
+        .with_file_name(&manifest.database)
+        .into_os_string()
+        .into_string()
+        .unwrap();
+    let data = std::fs::read(&database_path).expect("cannot read the database of the manifest");
+    let mut mm = lib::Context::load(database_path, data, lib::TypesetMode::Latex);
+    if let Err(error) = mm.set_manifest(manifest) {
+        panic!("{error}");
+    }
+    mm
 }
 
 fn main() -> eframe::Result {
     let title = "Proof stack game";
-    let mm = lib::Context::load(
-        "set.mm",
-        include_bytes!("/tmp/dump/set.mm"),
-        lib::TypesetMode::Latex,
-    );
-    let mut state = mm.initial_state(Some("dftru2"));
+    // A level pack manifest given on the command line replaces set.mm
+    let mm = match std::env::args().nth(1) {
+        Some(manifest_path) => load_level_pack(&manifest_path),
+        None => lib::Context::load(
+            "set.mm",
+            include_bytes!("/tmp/dump/set.mm"),
+            lib::TypesetMode::Latex,
+        ),
+    };
+    let mut state = mm.initial_state(mm.manifest().is_none().then_some("dftru2"));
+    // Some in goal-directed mode
+    let mut goal_tree: Option<lib::GoalTree> = None;
+    let mut progress = load_progress();
+    let mut lemmas = mm.load_lemmas(&progress);
+    let mut lemma_name = String::new();
+    let mut recording: Option<lib::Macro> = None;
+    // Arguments typed for each macro, separated by spaces
+    let mut macro_args: Vec<String> = progress
+        .macros
+        .iter()
+        .map(|mac| mac.params.join(" "))
+        .collect();
+    // Candidate action sequences of the last tactic run
+    let mut tactic_candidates: Vec<Vec<lib::Action>> = Vec::new();
+    // Stack entry whose truth table is shown
+    let mut truth_table_entry: Option<usize> = None;
+    // Most pushes a generated propositional proof may take
+    let mut prover_budget: usize = 10_000;
+    // Goal typed for sandbox mode, and the label before which assertions are available
+    let mut sandbox_goal_text = String::new();
+    let mut sandbox_before = String::new();
+    let mut sandbox_error: Option<String> = None;
+    // Search of open-library mode
+    let mut library_search = String::new();
+    let mut library_unifiable = false;
+    // Assertions found by the last search from the goal or a stack entry
+    let mut search_results: Vec<lib::SearchResult> = Vec::new();
+    // Buttons of the stack, recomputed only when the state changes
+    let mut button_cache = lib::ButtonCache::default();
+    // Buttons of goal-directed mode, for the goal tree they were computed for
+    let mut goal_tree_buttons = lib::Memo::default();
+    // TeX of the inference of each step
+    let mut inferences = lib::StepCache::default();
+    // Theorems unlock mode still needs, per state
+    let mut locked_deps_cache = lib::Memo::default();
+    // State after each macro, per state, number of lemmas and arguments
+    let mut macro_states = lib::Memo::default();
+    // Lemma each stack entry would make, per state, number of lemmas and name
+    let mut entry_lemmas = lib::Memo::default();
+    // Description of the level
+    let mut level_description = lib::Memo::default();
+    // Graph of the level map, for the level and number of solved levels it was built for
+    let mut level_map: Option<((lib::StatementAddress, usize), lib::DepGraph)> = None;
+    let mut map_zoom: f32 = 1.0;
+    // Level whose manifest hints are shown, and how many of them
+    let mut hints_shown = (state.current_level_stmt_addr, 0);
+    // Difficulty of the level, computed once per level as it walks the reference proof
+    let mut level_difficulty = lib::Memo::default();
+    // Proof of the finished level, exported once per state
+    let mut exported_proof = lib::Memo::default();
+    // Level after the finished one, per goal tree, state and level order
+    let mut next_level_cache = lib::Memo::default();
 
     eframe::run_simple_native(title, eframe::NativeOptions::default(), move |ctx, _| {
         egui_extras::install_image_loaders(ctx);
@@ -68,13 +378,244 @@ fn main() -> eframe::Result {
         egui::CentralPanel::default().show(ctx, |ui| {
             let level_addr = state.current_level_stmt_addr;
             let level_name = mm.label(level_addr);
-            let next_level = state.next_level(&mm);
-            let level_goal = render_inference(ctx.pixels_per_point(), &mm, level_addr);
+            let next_level_key = (
+                goal_tree.as_ref().map(|tree| tree.id()),
+                state.id(),
+                state.level_order,
+            );
+            let next_level = *next_level_cache.get(next_level_key, || match &goal_tree {
+                Some(tree) => tree
+                    .next_level(&mm)
+                    .map(|next| next.current_level_stmt_addr),
+                None => state
+                    .next_level(&mm)
+                    .map(|next| next.current_level_stmt_addr),
+            });
+            let level_goal = match state.sandbox_goal {
+                Some(_) => tex_to_image(ctx.pixels_per_point(), state.render_goal(&mm)),
+                None => render_inference(
+                    ctx.pixels_per_point(),
+                    &mm,
+                    &lemmas,
+                    &mut inferences,
+                    lib::Step::Stmt(level_addr),
+                ),
+            };
 
             ui.heading(title);
-            ui.heading(format!("Level {level_name}"));
+            let manifest_level = mm.manifest_level(level_addr);
+            match (
+                &state.sandbox_goal,
+                manifest_level.and_then(|level| level.title.as_ref()),
+            ) {
+                (Some(_), _) => ui.heading(format!("Sandbox before {level_name}")),
+                (None, Some(level_title)) => ui.heading(format!("{level_title} ({level_name})")),
+                (None, None) => ui.heading(format!("Level {level_name}")),
+            };
+            if state.sandbox_goal.is_none() {
+                let difficulty = level_difficulty.get(level_addr, || mm.difficulty(level_addr));
+                ui.label(format!("Difficulty {}", difficulty.score()));
+            }
+            if let Some(level) = manifest_level.filter(|_| state.sandbox_goal.is_none()) {
+                if !level.intro.is_empty() {
+                    ui.label(level.intro.as_str());
+                }
+                if let Some(par) = level.par {
+                    ui.label(format!("Par: {par} pushes"));
+                }
+                if hints_shown.0 != level_addr {
+                    hints_shown = (level_addr, 0);
+                }
+                for hint in &level.hints[..hints_shown.1] {
+                    ui.label(format!("Hint: {hint}"));
+                }
+                if hints_shown.1 < level.hints.len() && ui.button("Show a hint").clicked() {
+                    hints_shown.1 += 1;
+                }
+            }
+
+            let mut goal_directed = goal_tree.is_some();
+            if ui
+                .checkbox(&mut goal_directed, "Goal-directed mode")
+                .changed()
+            {
+                state = mm
+                    .initial_state(Some(&level_name))
+                    .with_library(state.library)
+                    .with_level_order(state.level_order)
+                    .with_progress(&mm, &progress);
+                goal_tree = goal_directed.then(|| {
+                    mm.initial_goal_tree(Some(&level_name))
+                        .with_level_order(state.level_order)
+                        .with_progress(&mm, &progress)
+                });
+            }
+            ui.horizontal(|ui| {
+                for library in lib::Library::ALL {
+                    if ui.radio(state.library == library, library.name()).clicked() {
+                        state = state.with_library(library);
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                for level_order in lib::LevelOrder::ALL {
+                    if ui
+                        .radio(state.level_order == level_order, level_order.name())
+                        .clicked()
+                    {
+                        state = state.with_level_order(level_order);
+                        goal_tree = goal_tree
+                            .as_ref()
+                            .map(|tree| tree.with_level_order(level_order));
+                    }
+                }
+            });
+            let locked_deps = locked_deps_cache.get(state.id(), || state.locked_deps(&mm));
+            if !locked_deps.is_empty() {
+                ui.horizontal(|ui| {
+                    ui.label("The reference proof uses theorems you haven't proven yet:");
+                    for &addr in locked_deps {
+                        let label = mm.label(addr);
+                        if ui.button(label.as_str()).clicked() {
+                            state = mm
+                                .initial_state(Some(&label))
+                                .with_library(state.library)
+                                .with_level_order(state.level_order)
+                                .with_progress(&mm, &progress);
+                            goal_tree = None;
+                        }
+                    }
+                });
+            }
+
+            egui::CollapsingHeader::new("Level map").show(ui, |ui| {
+                let key = (level_addr, progress.solved.len());
+                if level_map.as_ref().map(|(built_for, _)| *built_for) != Some(key) {
+                    let graph = mm.dep_graph(&mm.map_levels(level_addr), &progress);
+                    level_map = Some((key, graph));
+                }
+                let (_, graph) = level_map.as_ref().unwrap();
+                ui.horizontal(|ui| {
+                    ui.label("Zoom");
+                    ui.add(egui::Slider::new(&mut map_zoom, 0.25..=4.0).logarithmic(true));
+                });
+                if let Some(label) = level_map_ui(ui, graph, level_addr, map_zoom) {
+                    state = mm
+                        .initial_state(Some(&label))
+                        .with_library(state.library)
+                        .with_level_order(state.level_order)
+                        .with_progress(&mm, &progress);
+                    goal_tree = None;
+                }
+            });
+
+            if state.sandbox_goal.is_none() {
+                egui::CollapsingHeader::new("Description")
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        let description =
+                            level_description.get(level_addr, || mm.description(level_addr));
+                        if let Some(label) = description_ui(ui, ctx.pixels_per_point(), description)
+                        {
+                            state = mm
+                                .initial_state(Some(&label))
+                                .with_library(state.library)
+                                .with_level_order(state.level_order)
+                                .with_progress(&mm, &progress);
+                            goal_tree = None;
+                        }
+                    });
+            }
 
             ui.add(level_goal);
+            if state.sandbox_goal.is_some() && state.level_finished(&mm) {
+                ui.strong("Proved!");
+            }
+
+            egui::CollapsingHeader::new("Sandbox").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Goal");
+                    ui.text_edit_singleline(&mut sandbox_goal_text);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Assertions before (default: this level)");
+                    ui.text_edit_singleline(&mut sandbox_before);
+                });
+                if ui.button("Start").clicked() {
+                    let before = match sandbox_before.trim() {
+                        "" => Some(level_addr),
+                        label => mm.statement_addr(label),
+                    };
+                    let result = match before {
+                        Some(before) => {
+                            mm.sandbox_state(&sandbox_goal_text, before).map_err(|err| {
+                                // Point at the offending token, counting characters
+                                let column = sandbox_goal_text[..err.position].chars().count();
+                                let caret = " ".repeat(column);
+                                format!("{err}\n{sandbox_goal_text}\n{caret}^")
+                            })
+                        }
+                        None => Err(format!("Unknown label {sandbox_before}")),
+                    };
+                    match result {
+                        Ok(sandbox) => {
+                            state = sandbox;
+                            goal_tree = None;
+                            sandbox_error = None;
+                        }
+                        Err(err) => sandbox_error = Some(err),
+                    }
+                }
+                if let Some(err) = &sandbox_error {
+                    ui.label(
+                        egui::RichText::new(err)
+                            .monospace()
+                            .color(egui::Color32::RED),
+                    );
+                }
+            });
+
+            egui::CollapsingHeader::new("Truth table").show(ui, |ui| {
+                ui.label("Level goal");
+                truth_table_ui(ui, "goal", mm.truth_table(&state.goal(&mm)));
+                if let Some(entry) = truth_table_entry.and_then(|i| state.proof_stack.get(i)) {
+                    ui.separator();
+                    ui.label("Stack entry");
+                    truth_table_ui(ui, "entry", mm.truth_table(&entry.expr));
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if ui.button("What could produce the goal?").clicked() {
+                    search_results = state.search_producers(&mm);
+                }
+                if !search_results.is_empty() && ui.button("Clear search").clicked() {
+                    search_results.clear();
+                }
+            });
+            egui::ScrollArea::vertical()
+                .id_salt("search")
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    for result in &search_results {
+                        ui.horizontal(|ui| {
+                            let part = match result.matched {
+                                lib::Matched::Conclusion => String::from("conclusion"),
+                                lib::Matched::Hyp(i) => format!("hypothesis {}", i + 1),
+                            };
+                            ui.label(format!("{} ({part})", mm.label(result.addr)));
+                            if !result.substs.is_empty() {
+                                let tex = result
+                                    .substs
+                                    .iter()
+                                    .map(|(var, value)| format!("{var} \\mapsto {value}"))
+                                    .collect::<Vec<_>>()
+                                    .join(", \\quad ");
+                                ui.add(tex_to_image(ctx.pixels_per_point(), tex));
+                            }
+                        });
+                    }
+                });
 
             ui.columns(2, |uis| {
                 // Deps
@@ -83,64 +624,270 @@ fn main() -> eframe::Result {
                         .id_salt(0)
                         .drag_to_scroll(false)
                         .show(ui, |ui| match next_level {
-                            Some(next_state) => {
+                            Some(next_level_addr) => {
+                                let proof = exported_proof.get((state.id(), lemmas.len()), || {
+                                    state.export_proof(&mm, &lemmas)
+                                });
+                                if let Some(proof) = proof {
+                                    ui.label("Proof");
+                                    ui.label(egui::RichText::new(proof).monospace());
+                                }
                                 if ui.button("Next level").clicked() {
-                                    state = next_state;
+                                    progress.record_solved(level_name.clone());
+                                    save_progress(&progress);
+                                    let next_level_name = mm.label(next_level_addr);
+                                    state = mm
+                                        .initial_state(Some(&next_level_name))
+                                        .with_library(state.library)
+                                        .with_level_order(state.level_order)
+                                        .with_progress(&mm, &progress);
+                                    if goal_tree.is_some() {
+                                        goal_tree = Some(
+                                            mm.initial_goal_tree(Some(&next_level_name))
+                                                .with_level_order(state.level_order)
+                                                .with_progress(&mm, &progress),
+                                        );
+                                    }
                                 }
                             }
-                            None => {
-                                for (stmt_addr, opt_next_state) in state.buttons(&mm) {
-                                    let image =
-                                        render_inference(ctx.pixels_per_point(), &mm, stmt_addr);
-                                    match opt_next_state {
-                                        Some(next_state) => {
-                                            if ui
-                                                .add(egui::ImageButton::new(
-                                                    image.bg_fill(egui::Color32::LIGHT_GRAY),
-                                                ))
-                                                .clicked()
-                                            {
-                                                state = next_state;
-                                            }
+                            None => match &goal_tree {
+                                Some(tree) => {
+                                    let buttons =
+                                        goal_tree_buttons.get(tree.id(), || tree.buttons(&mm));
+                                    if let Some(i) = dep_buttons(
+                                        ui,
+                                        ctx.pixels_per_point(),
+                                        &mm,
+                                        &lemmas,
+                                        &mut inferences,
+                                        buttons.iter().copied(),
+                                    ) {
+                                        goal_tree = tree.apply(&mm, buttons[i].0);
+                                    }
+                                }
+                                None => {
+                                    let buttons = match state.library {
+                                        lib::Library::Reference => {
+                                            button_cache.buttons(&mm, &lemmas, &state)
                                         }
-                                        None => {
-                                            ui.add(egui::ImageButton::new(
-                                                image.bg_fill(egui::Color32::GRAY),
-                                            ));
+                                        lib::Library::Open | lib::Library::Unlocked => {
+                                            ui.horizontal(|ui| {
+                                                ui.label("Search");
+                                                ui.text_edit_singleline(&mut library_search);
+                                            });
+                                            ui.checkbox(
+                                                &mut library_unifiable,
+                                                "Unifies with the goal or a stack entry",
+                                            );
+                                            button_cache.search_library(
+                                                &mm,
+                                                &lemmas,
+                                                &state,
+                                                &library_search,
+                                                library_unifiable,
+                                            )
                                         }
+                                    };
+                                    if let Some(i) = dep_buttons(
+                                        ui,
+                                        ctx.pixels_per_point(),
+                                        &mm,
+                                        &lemmas,
+                                        &mut inferences,
+                                        buttons.iter().copied(),
+                                    ) {
+                                        let action = lib::Action::Push(buttons[i].0);
+                                        act(&mm, &lemmas, &mut state, &mut recording, action);
                                     }
                                 }
-                            }
+                            },
                         });
                 });
 
-                // Stack
-                uis[1].vertical(|ui| {
-                    egui::ScrollArea::both()
-                        .id_salt(1)
-                        .drag_to_scroll(false)
-                        .show(ui, |ui| {
-                            for (i, expr) in state.render_stack(&mm).into_iter().enumerate() {
-                                let image = tex_to_image(ctx.pixels_per_point(), expr.clone());
-                                let id = egui::Id::new(i);
+                // Open goals
+                if let Some(tree) = &goal_tree {
+                    let mut next_tree = None;
+                    uis[1].vertical(|ui| {
+                        egui::ScrollArea::both()
+                            .id_salt(1)
+                            .drag_to_scroll(false)
+                            .show(ui, |ui| {
+                                for (i, expr) in tree.render_goals(&mm).into_iter().enumerate() {
+                                    let image = tex_to_image(ctx.pixels_per_point(), expr);
+                                    ui.horizontal(|ui| {
+                                        if ui.radio(tree.selected == i, "").clicked() {
+                                            next_tree = tree.select(i);
+                                        }
+                                        ui.add(image);
+                                    });
+                                }
+                            });
+                    });
+                    if next_tree.is_some() {
+                        goal_tree = next_tree;
+                    }
+                } else {
+                    // Stack
+                    uis[1].vertical(|ui| {
+                        egui::ScrollArea::both()
+                            .id_salt(1)
+                            .drag_to_scroll(false)
+                            .show(ui, |ui| {
+                                ui.horizontal(|ui| match &mut recording {
+                                    Some(mac) => {
+                                        ui.label("Macro name");
+                                        ui.text_edit_singleline(&mut mac.name);
+                                        if ui
+                                            .add_enabled(
+                                                !mac.name.is_empty(),
+                                                egui::Button::new("Save macro"),
+                                            )
+                                            .clicked()
+                                        {
+                                            macro_args.push(mac.params.join(" "));
+                                            progress.macros.push(recording.take().unwrap());
+                                            save_progress(&progress);
+                                        } else if ui.button("Cancel").clicked() {
+                                            recording = None;
+                                        }
+                                    }
+                                    None => {
+                                        if ui.button("Record macro").clicked() {
+                                            recording = Some(lib::Macro::new(""));
+                                        }
+                                    }
+                                });
+                                let key = (state.id(), lemmas.len(), macro_args.clone());
+                                let next_states = macro_states.get(key, || {
+                                    progress
+                                        .macros
+                                        .iter()
+                                        .zip(&macro_args)
+                                        .map(|(mac, args)| {
+                                            let args = args
+                                                .split_whitespace()
+                                                .map(String::from)
+                                                .collect::<Vec<_>>();
+                                            state.run_macro(&mm, &lemmas, mac, &args)
+                                        })
+                                        .collect::<Vec<_>>()
+                                });
+                                let mut next_macro_state = None;
+                                for ((mac, args), next_state) in
+                                    progress.macros.iter().zip(&mut macro_args).zip(next_states)
+                                {
+                                    ui.horizontal(|ui| {
+                                        if ui
+                                            .add_enabled(
+                                                next_state.is_some(),
+                                                egui::Button::new(mac.name.as_str()),
+                                            )
+                                            .clicked()
+                                        {
+                                            next_macro_state = next_state.clone();
+                                        }
+                                        ui.text_edit_singleline(args);
+                                    });
+                                }
+                                if let Some(next_state) = next_macro_state {
+                                    state = next_state;
+                                }
                                 ui.horizontal(|ui| {
-                                    if ui.button("Del").clicked() {
-                                        state = state.stack_delete(i).unwrap();
+                                    for tactic in lib::Tactic::ALL {
+                                        if ui.button(tactic.name()).clicked() {
+                                            tactic_candidates = state.tactic(&mm, &lemmas, tactic);
+                                        }
                                     }
-                                    if ui.button("Cpy").clicked() {
-                                        state = state.stack_copy(i).unwrap();
+                                    if ui.button("Prove").clicked() {
+                                        tactic_candidates = state
+                                            .prove_prop(&mm, &lemmas, prover_budget)
+                                            .into_iter()
+                                            .collect();
                                     }
-                                    if let Some(j) = ui
-                                        .dnd_drag_source(id, i, |ui| ui.add(image))
-                                        .response
-                                        .dnd_release_payload()
-                                    {
-                                        state = state.stack_move(*j, i).unwrap();
+                                    ui.add(
+                                        egui::DragValue::new(&mut prover_budget)
+                                            .range(1..=100_000)
+                                            .suffix(" steps"),
+                                    );
+                                });
+                                let mut picked = None;
+                                for actions in &tactic_candidates {
+                                    // Candidates from an earlier state may no longer apply
+                                    let Some(preview) = state.apply_all(&mm, &lemmas, actions)
+                                    else {
+                                        continue;
+                                    };
+                                    ui.horizontal(|ui| {
+                                        if ui.button("Apply").clicked() {
+                                            picked = Some(actions.clone());
+                                        }
+                                        if let Some(top) = preview.render_stack(&mm).pop() {
+                                            ui.add(tex_to_image(ctx.pixels_per_point(), top));
+                                        }
+                                    });
+                                }
+                                if let Some(actions) = picked {
+                                    for action in actions {
+                                        act(&mm, &lemmas, &mut state, &mut recording, action);
                                     }
+                                    tactic_candidates.clear();
+                                }
+                                ui.horizontal(|ui| {
+                                    ui.label("Lemma name");
+                                    ui.text_edit_singleline(&mut lemma_name);
                                 });
-                            }
-                        });
-                });
+                                let key = (state.id(), lemmas.len(), lemma_name.clone());
+                                let lemma_of_entry = entry_lemmas.get(key, || {
+                                    (0..state.proof_stack.len())
+                                        .map(|i| state.lemma(&mm, &lemmas, i, &lemma_name))
+                                        .collect::<Vec<_>>()
+                                });
+                                for (i, expr) in state.render_stack(&mm).into_iter().enumerate() {
+                                    let image = tex_to_image(ctx.pixels_per_point(), expr.clone());
+                                    let id = egui::Id::new(i);
+                                    ui.horizontal(|ui| {
+                                        if ui.button("Del").clicked() {
+                                            let action = lib::Action::Delete(i);
+                                            act(&mm, &lemmas, &mut state, &mut recording, action);
+                                        }
+                                        if ui.button("Cpy").clicked() {
+                                            let action = lib::Action::Copy(i);
+                                            act(&mm, &lemmas, &mut state, &mut recording, action);
+                                        }
+                                        if ui.button("TT").clicked() {
+                                            truth_table_entry = Some(i);
+                                        }
+                                        if ui
+                                            .button("?")
+                                            .on_hover_text("What can I do with this?")
+                                            .clicked()
+                                        {
+                                            search_results = state.search_uses(&mm, i);
+                                        }
+                                        let lemma = &lemma_of_entry[i];
+                                        if ui
+                                            .add_enabled(lemma.is_some(), egui::Button::new("Lem"))
+                                            .clicked()
+                                        {
+                                            let lemma = lemma.clone().unwrap();
+                                            progress.lemmas.push(mm.save_lemma(&lemma, &lemmas));
+                                            save_progress(&progress);
+                                            lemmas.push(lemma);
+                                            lemma_name.clear();
+                                        }
+                                        if let Some(j) = ui
+                                            .dnd_drag_source(id, i, |ui| ui.add(image))
+                                            .response
+                                            .dnd_release_payload()
+                                        {
+                                            let action = lib::Action::Move(*j, i);
+                                            act(&mm, &lemmas, &mut state, &mut recording, action);
+                                        }
+                                    });
+                                }
+                            });
+                    });
+                }
             });
         });
     })
@@ -0,0 +1,66 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use itertools::Itertools;
+use lib::{Action, Context, Entry, Expr, State, TypesetMode, WorkSubsts};
+
+/// Levels of set.mm with many hypotheses, which made matching them slow
+const LEVELS: [&str; 4] = ["mp3an", "syl3anc", "syl23anc", "syl33anc"];
+
+/// Every hypothesis of the level on the stack, so that each dependency tries to use them
+fn level_with_hyps(ctx: &Context, level: &str) -> State {
+    assert!(
+        ctx.statement_addr(level).is_some(),
+        "level {level} is not in the database"
+    );
+    let state = ctx.initial_state(Some(level));
+    let hyp_prefix = format!("{level}.");
+    let push_hyps = state
+        .buttons(ctx, &[])
+        .into_iter()
+        .filter(|(step, _)| ctx.step_label(*step, &[]).starts_with(&hyp_prefix))
+        .map(|(step, _)| Action::Push(step))
+        .collect::<Vec<_>>();
+    state.apply_all(ctx, &[], &push_hyps).unwrap()
+}
+
+/// The matcher pushes used before matching essential hypotheses in order,
+/// trying every order of the hypotheses
+fn match_hyps_baseline(
+    ctx: &Context,
+    hyps: &[(bool, Expr)],
+    entries: &[Entry],
+) -> Option<(WorkSubsts, Vec<usize>)> {
+    (0..hyps.len()).permutations(hyps.len()).find_map(|order| {
+        let (skipped, matched) = order.split_at(order.len() - entries.len());
+        // Ensure no essential hypotheses are ignored
+        if skipped.iter().any(|&i| hyps[i].0) {
+            return None;
+        }
+        let mut substs = WorkSubsts::new();
+        for (entry, &i) in entries.iter().zip(matched) {
+            if !ctx.unify_hyp(&entry.expr, &hyps[i].1, &mut substs) {
+                return None;
+            }
+        }
+        Some((substs, matched.to_vec()))
+    })
+}
+
+fn buttons(c: &mut Criterion) {
+    let path = std::env::var("SET_MM").expect("SET_MM, the path of set.mm");
+    let data = std::fs::read(&path).unwrap_or_else(|err| panic!("reading {path}: {err}"));
+    let ctx = Context::load("set.mm", data, TypesetMode::Latex);
+    let mut group = c.benchmark_group("buttons");
+    // The baseline takes seconds per run on the larger levels
+    group.sample_size(10);
+    for level in LEVELS {
+        let state = level_with_hyps(&ctx, level);
+        group.bench_function(level, |b| b.iter(|| state.buttons(&ctx, &[])));
+        group.bench_function(format!("{level} baseline"), |b| {
+            b.iter(|| state.buttons_with(&ctx, &[], match_hyps_baseline))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, buttons);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+//! Prints the manifest of a campaign leading to a theorem,
+//! e.g. `cargo run --release -p lib --bin campaign -- /tmp/dump/set.mm pm2.18 30 > pm2.18.json`
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let (input, target, max_prerequisites) = match args.as_slice() {
+        [_, input, target] => (input, target, None),
+        [_, input, target, max] => (input, target, Some(max.parse().expect("invalid number"))),
+        _ => {
+            eprintln!("usage: campaign <database.mm> <target label> [max prerequisites]");
+            std::process::exit(2);
+        }
+    };
+    let data = std::fs::read(input).expect("cannot read the database");
+    let ctx = lib::Context::load(input.clone(), data, lib::TypesetMode::Latex);
+    let target = ctx
+        .statement_addr(target)
+        .unwrap_or_else(|| panic!("no statement {target}"));
+    let mut manifest = ctx.campaign(target, max_prerequisites);
+    // The manifest is meant to be written next to the database
+    manifest.database = std::path::Path::new(input)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    println!("{}", manifest.to_json());
+}
@@ -0,0 +1,36 @@
+//! Prints the dependency graph of some levels as Graphviz DOT or JSON,
+//! e.g. `cargo run --release -p lib --bin graph -- /tmp/dump/set.mm dot idi..mpd | dot -Tsvg`.
+//! Levels solved in a progress file given with `--progress <file.json>` are marked.
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let progress = match args.iter().position(|arg| arg == "--progress") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.drain(i..i + 2).nth(1).unwrap();
+            let text = std::fs::read_to_string(path).expect("cannot read the progress");
+            serde_json::from_str(&text).expect("invalid progress")
+        }
+        _ => lib::Progress::default(),
+    };
+    let [input, format, levels @ ..] = args.as_slice() else {
+        eprintln!(
+            "usage: graph [--progress <file.json>] <database.mm> <dot|json> <label or first..last>..."
+        );
+        std::process::exit(2);
+    };
+    let data = std::fs::read(input).expect("cannot read the database");
+    let ctx = lib::Context::load(input.clone(), data, lib::TypesetMode::Latex);
+    let levels = levels
+        .iter()
+        .flat_map(|spec| {
+            ctx.parse_levels(spec)
+                .unwrap_or_else(|error| panic!("{error}"))
+        })
+        .collect::<Vec<_>>();
+    let graph = ctx.dep_graph(&levels, &progress);
+    match format.as_str() {
+        "dot" => print!("{}", graph.to_dot()),
+        "json" => println!("{}", graph.to_json()),
+        _ => panic!("unknown format {format}"),
+    }
+}
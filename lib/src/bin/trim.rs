@@ -0,0 +1,20 @@
+//! Writes a database with only what some levels need, for shipping a level pack,
+//! e.g. `cargo run --release -p lib --bin trim -- /tmp/dump/set.mm pack.mm idi..mpd pm2.18`
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let [_, input, output, levels @ ..] = args.as_slice() else {
+        eprintln!("usage: trim <database.mm> <output.mm> <label or first..last>...");
+        std::process::exit(2);
+    };
+    let data = std::fs::read(input).expect("cannot read the database");
+    let ctx = lib::Context::load(input.clone(), data, lib::TypesetMode::Latex);
+    let levels = levels
+        .iter()
+        .flat_map(|spec| {
+            ctx.parse_levels(spec)
+                .unwrap_or_else(|error| panic!("{error}"))
+        })
+        .collect::<Vec<_>>();
+    std::fs::write(output, ctx.trim(&levels)).expect("cannot write the database");
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use crate::{Context, Lemma, State, Step};
+
+/// A value computed for the last key it was asked for,
+/// so that frontends can ask for it on every frame
+pub struct Memo<K, V> {
+    last: Option<(K, V)>,
+}
+
+impl<K, V> Default for Memo<K, V> {
+    fn default() -> Self {
+        Self { last: None }
+    }
+}
+
+impl<K: PartialEq, V> Memo<K, V> {
+    pub fn get(&mut self, key: K, compute: impl FnOnce() -> V) -> &V {
+        if self.last.as_ref().map(|(last_key, _)| last_key) != Some(&key) {
+            self.last = Some((key, compute()));
+        }
+        &self.last.as_ref().unwrap().1
+    }
+}
+
+/// Values computed once per step, such as its rendered inference.
+/// Lemmas are only ever added, so a lemma step keeps its meaning.
+pub struct StepCache<T> {
+    values: HashMap<Step, T>,
+}
+
+impl<T> Default for StepCache<T> {
+    fn default() -> Self {
+        Self {
+            values: HashMap::new(),
+        }
+    }
+}
+
+impl<T> StepCache<T> {
+    pub fn get(&mut self, step: Step, compute: impl FnOnce() -> T) -> &T {
+        self.values.entry(step).or_insert_with(compute)
+    }
+}
+
+/// What the cached buttons were computed for
+#[derive(PartialEq)]
+struct ButtonKey {
+    state_id: u64,
+    /// Lemmas are only ever added, so their number identifies them
+    num_lemmas: usize,
+    /// Text and unification filter of an open-library search
+    search: Option<(String, bool)>,
+}
+
+/// Buttons of the last state they were computed for,
+/// so that frontends can ask for them on every frame
+#[derive(Default)]
+pub struct ButtonCache {
+    buttons: Memo<ButtonKey, Vec<(Step, bool)>>,
+}
+
+impl ButtonCache {
+    pub fn buttons(&mut self, ctx: &Context, lemmas: &[Lemma], state: &State) -> &[(Step, bool)] {
+        self.get_or_compute(lemmas, state, None, || state.buttons(ctx, lemmas))
+    }
+
+    pub fn search_library(
+        &mut self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        state: &State,
+        text: &str,
+        unifiable: bool,
+    ) -> &[(Step, bool)] {
+        let search = Some((text.into(), unifiable));
+        self.get_or_compute(lemmas, state, search, || {
+            state.search_library(ctx, lemmas, text, unifiable)
+        })
+    }
+
+    fn get_or_compute(
+        &mut self,
+        lemmas: &[Lemma],
+        state: &State,
+        search: Option<(String, bool)>,
+        compute: impl FnOnce() -> Vec<(Step, bool)>,
+    ) -> &[(Step, bool)] {
+        let key = ButtonKey {
+            state_id: state.id(),
+            num_lemmas: lemmas.len(),
+            search,
+        };
+        self.buttons.get(key, compute)
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+
+use metamath_rs::{statement::StatementAddress, StatementType};
+
+use crate::{Context, Manifest, ManifestLevel};
+
+impl Context {
+    /// Whether a statement is a theorem with a provable conclusion,
+    /// rather than a hypothesis, an axiom or a syntax theorem
+    fn is_theorem(&self, addr: StatementAddress) -> bool {
+        let db = &self.metamath_db;
+        db.statement_by_address(addr).statement_type() == StatementType::Provable
+            && self
+                .stmt_expr(addr)
+                .is_some_and(|expr| expr.typecode == db.grammar_result().provable_typecode())
+    }
+
+    /// Theorems the proof of `target` uses, transitively, each after the theorems
+    /// its own proof uses, ending with `target`
+    pub fn prerequisites(&self, target: StatementAddress) -> Vec<StatementAddress> {
+        let mut out = Vec::new();
+        let mut visited = HashSet::new();
+        // Theorems with whether their dependencies are already on the stack
+        let mut stack = vec![(target, false)];
+        while let Some((addr, expanded)) = stack.pop() {
+            if expanded {
+                out.push(addr);
+            } else if visited.insert(addr) {
+                stack.push((addr, true));
+                stack.extend(
+                    self.deps(addr)
+                        .into_iter()
+                        .filter(|&dep| dep != addr && self.is_theorem(dep))
+                        .map(|dep| (dep, false)),
+                );
+            }
+        }
+        out
+    }
+
+    /// Campaign leading to `target` through its prerequisites,
+    /// keeping only the `max_prerequisites` hardest ones if given
+    pub fn campaign(&self, target: StatementAddress, max_prerequisites: Option<usize>) -> Manifest {
+        let mut levels = self.prerequisites(target);
+        levels.pop();
+        if let Some(max) = max_prerequisites {
+            let mut hardest = levels.clone();
+            hardest.sort_by_key(|&addr| std::cmp::Reverse(self.difficulty(addr).score()));
+            let hardest = hardest.into_iter().take(max).collect::<HashSet<_>>();
+            levels.retain(|addr| hardest.contains(addr));
+        }
+        levels.push(target);
+        let target_label = self.label(target);
+        Manifest {
+            title: format!("Path to {target_label}"),
+            database: String::new(),
+            levels: levels
+                .into_iter()
+                .map(|addr| ManifestLevel {
+                    label: self.label(addr),
+                    ..ManifestLevel::default()
+                })
+                .collect(),
+        }
+    }
+}
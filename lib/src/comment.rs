@@ -0,0 +1,270 @@
+use metamath_rs::{statement::StatementAddress, StatementType};
+
+use crate::Context;
+
+/// A piece of the description of a statement, parsed from Metamath comment markup
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommentPart {
+    Text(String),
+    /// Blank line between paragraphs
+    ParagraphBreak,
+    /// `_italic_` text
+    Italic(String),
+    /// Math between backticks
+    Math {
+        /// Math tokens separated by spaces, as written in the database
+        source: String,
+        /// Rendered in the typeset mode
+        rendered: String,
+    },
+    /// `~ label` cross-reference
+    Label {
+        label: String,
+        /// Whether the statement is provable, so that it can be played as a level
+        is_level: bool,
+    },
+    /// `~ url` link
+    Url(String),
+    /// `[Author]` bibliography tag, without the brackets
+    Citation(String),
+}
+
+impl CommentPart {
+    /// Text of a description without markup, e.g. for tooltips
+    pub fn plain_text(parts: &[Self]) -> String {
+        let mut out = String::new();
+        for part in parts {
+            match part {
+                Self::Text(text) | Self::Italic(text) | Self::Url(text) => out.push_str(text),
+                Self::ParagraphBreak => out.push_str("\n\n"),
+                Self::Math { source, .. } => out.push_str(source),
+                Self::Label { label, .. } => out.push_str(label),
+                Self::Citation(tag) => {
+                    out.push('[');
+                    out.push_str(tag);
+                    out.push(']');
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Characters of `text` from `start` up to the first one satisfying `end`
+fn take_until(text: &[char], start: usize, end: impl Fn(char) -> bool) -> String {
+    text[start..].iter().take_while(|&&c| !end(c)).collect()
+}
+
+impl Context {
+    /// Description of a statement from the comment preceding it, empty if there is none
+    pub fn description(&self, addr: StatementAddress) -> Vec<CommentPart> {
+        let stmt = self.metamath_db.statement_by_address(addr);
+        let Some(comment) = stmt.associated_comment() else {
+            return Vec::new();
+        };
+        let (buf, span) = comment.comment_contents();
+        self.parse_comment(&String::from_utf8_lossy(span.as_ref(buf)))
+    }
+
+    fn parse_comment(&self, text: &str) -> Vec<CommentPart> {
+        let chars = text.trim().chars().collect::<Vec<_>>();
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let flush = |text: &mut String, parts: &mut Vec<CommentPart>| {
+            if !text.is_empty() {
+                parts.push(CommentPart::Text(std::mem::take(text)));
+            }
+        };
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            let at_word_start = i == 0 || !chars[i - 1].is_alphanumeric();
+            match c {
+                // Doubled markup characters stand for themselves
+                '`' | '~' if next == Some(c) => {
+                    text.push(c);
+                    i += 2;
+                }
+                '`' => {
+                    let mut math = String::new();
+                    i += 1;
+                    while i < chars.len() {
+                        if chars[i] == '`' {
+                            if chars.get(i + 1) != Some(&'`') {
+                                break;
+                            }
+                            i += 1;
+                        }
+                        math.push(chars[i]);
+                        i += 1;
+                    }
+                    i += 1;
+                    flush(&mut text, &mut parts);
+                    let toks = math.split_whitespace().map(str::as_bytes);
+                    parts.push(CommentPart::Math {
+                        source: math.split_whitespace().collect::<Vec<_>>().join(" "),
+                        rendered: self.render_tokens(toks),
+                    });
+                }
+                '~' => {
+                    let start = i
+                        + 1
+                        + chars[i + 1..]
+                            .iter()
+                            .take_while(|c| c.is_whitespace())
+                            .count();
+                    let target = take_until(&chars, start, char::is_whitespace);
+                    i = start + target.chars().count();
+                    flush(&mut text, &mut parts);
+                    let part = match self.metamath_db.statement(target.as_bytes()) {
+                        Some(stmt) => CommentPart::Label {
+                            is_level: stmt.statement_type() == StatementType::Provable,
+                            label: target,
+                        },
+                        None if target.starts_with("http") => CommentPart::Url(target),
+                        None => CommentPart::Text(target),
+                    };
+                    parts.push(part);
+                }
+                '[' if at_word_start => {
+                    let tag = take_until(&chars, i + 1, |c| c == ']' || c.is_whitespace());
+                    let end = i + 1 + tag.chars().count();
+                    if !tag.is_empty() && chars.get(end) == Some(&']') {
+                        flush(&mut text, &mut parts);
+                        parts.push(CommentPart::Citation(tag));
+                        i = end + 1;
+                    } else {
+                        text.push(c);
+                        i += 1;
+                    }
+                }
+                '_' if at_word_start && next.is_some_and(char::is_alphanumeric) => {
+                    // Closed by an underscore ending a word
+                    let end = (i + 1..chars.len()).find(|&j| {
+                        chars[j] == '_' && chars.get(j + 1).is_none_or(|c| !c.is_alphanumeric())
+                    });
+                    match end {
+                        Some(end) => {
+                            flush(&mut text, &mut parts);
+                            parts.push(CommentPart::Italic(chars[i + 1..end].iter().collect()));
+                            i = end + 1;
+                        }
+                        None => {
+                            text.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                c if c.is_whitespace() => {
+                    let len = chars[i..].iter().take_while(|c| c.is_whitespace()).count();
+                    let num_newlines = chars[i..i + len].iter().filter(|&&c| c == '\n').count();
+                    if num_newlines >= 2 {
+                        flush(&mut text, &mut parts);
+                        parts.push(CommentPart::ParagraphBreak);
+                    } else {
+                        text.push(' ');
+                    }
+                    i += len;
+                }
+                _ => {
+                    text.push(c);
+                    i += 1;
+                }
+            }
+        }
+        flush(&mut text, &mut parts);
+        parts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommentPart::{self, *};
+    use crate::expr::tests::context;
+
+    fn parse(text: &str) -> Vec<CommentPart> {
+        context().parse_comment(text)
+    }
+
+    fn text(text: &str) -> CommentPart {
+        Text(text.into())
+    }
+
+    fn math(source: &str) -> CommentPart {
+        Math {
+            source: source.into(),
+            rendered: source.into(),
+        }
+    }
+
+    #[test]
+    fn links_to_statements_and_urls() {
+        let label = |label: &str, is_level| Label {
+            label: label.into(),
+            is_level,
+        };
+        assert_eq!(
+            parse("See ~ a1 and ~  ax-mp or ~ https://us.metamath.org"),
+            [
+                text("See "),
+                label("a1", true),
+                text(" and "),
+                label("ax-mp", false),
+                text(" or "),
+                Url("https://us.metamath.org".into()),
+            ]
+        );
+        assert_eq!(parse("~ nothing"), [text("nothing")]);
+    }
+
+    #[test]
+    fn math_between_backticks() {
+        assert_eq!(
+            parse("Infer ` ps `  from\n` ( ph  -> ps ) `."),
+            [
+                text("Infer "),
+                math("ps"),
+                text(" from "),
+                math("( ph -> ps )"),
+                text("."),
+            ]
+        );
+    }
+
+    #[test]
+    fn italics_and_citations() {
+        assert_eq!(
+            parse("_Modus ponens_, see [Margaris] p. 40, not snake_case or a[1]"),
+            [
+                Italic("Modus ponens".into()),
+                text(", see "),
+                Citation("Margaris".into()),
+                text(" p. 40, not snake_case or a[1]"),
+            ]
+        );
+        assert_eq!(parse("_open"), [text("_open")]);
+    }
+
+    #[test]
+    fn doubled_markup_escapes_itself() {
+        assert_eq!(parse("a ~~ b `` c"), [text("a ~ b ` c")]);
+        assert_eq!(parse("` a `` b `"), [math("a ` b")]);
+    }
+
+    #[test]
+    fn unterminated_math_runs_to_the_end() {
+        assert_eq!(
+            parse("Infer ` ph -> ps"),
+            [text("Infer "), math("ph -> ps")]
+        );
+    }
+
+    #[test]
+    fn blank_lines_break_paragraphs() {
+        assert_eq!(
+            parse("One.\n\n  Two."),
+            [text("One."), ParagraphBreak, text("Two.")]
+        );
+    }
+}
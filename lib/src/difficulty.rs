@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use metamath_rs::{statement::StatementAddress, StatementType};
+
+use crate::Context;
+
+/// Order in which levels follow each other
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LevelOrder {
+    /// Database order
+    #[default]
+    Database,
+    /// Each chapter sorted by difficulty, easiest first
+    Gentle,
+}
+
+impl LevelOrder {
+    pub const ALL: [Self; 2] = [Self::Database, Self::Gentle];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Database => "Database order",
+            Self::Gentle => "Easiest first in each chapter",
+        }
+    }
+}
+
+/// Features of the reference proof of a level which make it harder
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Difficulty {
+    /// Number of pushes of the reference proof
+    pub proof_len: usize,
+    /// Number of distinct assertions it uses
+    pub num_deps: usize,
+    /// Most essential hypotheses of an assertion it uses
+    pub max_hyps: usize,
+    /// Whether it uses an assertion with hypotheses having variables its conclusion lacks,
+    /// which are work variables unless the hypotheses are on the stack
+    pub needs_work_vars: bool,
+    /// Whether it first uses the hypotheses of the level in another order than they are stated
+    pub needs_reordering: bool,
+}
+
+impl Difficulty {
+    /// Weighted sum of the features, comparable between levels
+    pub fn score(&self) -> usize {
+        self.proof_len
+            + 2 * self.num_deps
+            + 3 * self.max_hyps
+            + 10 * usize::from(self.needs_work_vars)
+            + 10 * usize::from(self.needs_reordering)
+    }
+}
+
+impl Context {
+    /// Whether some essential hypothesis of an assertion has a variable its conclusion lacks
+    fn has_hidden_vars(&self, addr: StatementAddress) -> bool {
+        let Some(rule) = self.rule(addr) else {
+            return false;
+        };
+        let mut conclusion_vars = HashSet::new();
+        rule.conclusion.tree.for_each_var(&mut |var| {
+            conclusion_vars.insert(var);
+        });
+        let mut hidden = false;
+        for (_, hyp) in rule.hyps.iter().filter(|(essential, _)| *essential) {
+            hyp.tree
+                .for_each_var(&mut |var| hidden |= !conclusion_vars.contains(&var));
+        }
+        hidden
+    }
+
+    /// Difficulty of a level from its reference proof, the default if the proof is incomplete
+    pub fn difficulty(&self, addr: StatementAddress) -> Difficulty {
+        let difficulties = self.difficulties.get_or_init(|| {
+            let levels = self.chapters().levels.iter().flatten();
+            levels
+                .map(|&level| (level, self.compute_difficulty(level)))
+                .collect::<HashMap<_, _>>()
+        });
+        match difficulties.get(&addr) {
+            Some(&difficulty) => difficulty,
+            None => self.compute_difficulty(addr),
+        }
+    }
+
+    fn compute_difficulty(&self, addr: StatementAddress) -> Difficulty {
+        let db = &self.metamath_db;
+        let names = db.name_result();
+        let provable_typecode = db.grammar_result().provable_typecode();
+        let Some(proof_tree) = db.get_proof_tree(db.statement_by_address(addr)) else {
+            return Difficulty::default();
+        };
+        // Each step with the number of pushes of its subproof, syntax steps taking none
+        let steps = proof_tree.with_steps(db, |_cur, stmt, hyps: &[(StatementAddress, usize)]| {
+            let is_provable = names.get_atom(stmt.math_at(0).slice) == provable_typecode;
+            let len = usize::from(is_provable) + hyps.iter().map(|(_, len)| len).sum::<usize>();
+            (stmt.address(), len)
+        });
+        let deps = steps
+            .iter()
+            .map(|&(dep, _)| dep)
+            .unique()
+            .filter(|&dep| {
+                let stmt = db.statement_by_address(dep);
+                matches!(
+                    stmt.statement_type(),
+                    StatementType::Axiom | StatementType::Provable
+                ) && names.get_atom(stmt.math_at(0).slice) == provable_typecode
+            })
+            .collect::<Vec<_>>();
+        let first_uses = self
+            .essential_hyps(addr)
+            .into_iter()
+            .filter_map(|hyp| steps.iter().position(|&(step, _)| step == hyp))
+            .collect::<Vec<_>>();
+        Difficulty {
+            proof_len: steps.iter().map(|&(_, len)| len).max().unwrap_or(0),
+            num_deps: deps.len(),
+            max_hyps: deps
+                .iter()
+                .map(|&dep| self.essential_hyps(dep).len())
+                .max()
+                .unwrap_or(0),
+            needs_work_vars: deps.iter().any(|&dep| self.has_hidden_vars(dep)),
+            needs_reordering: !first_uses.is_sorted(),
+        }
+    }
+
+    /// The level after `addr` in gentle order: the next harder unsolved level of its chapter,
+    /// or else its easiest unsolved level, or else the easiest level of the next chapter
+    pub(crate) fn next_gentle_level(
+        &self,
+        addr: StatementAddress,
+        solved: &HashSet<StatementAddress>,
+    ) -> Option<StatementAddress> {
+        let chapters = self.chapters();
+        let Some(&chapter) = chapters.chapter_of.get(&addr) else {
+            // A level the filter skips continues with the next level in database order
+            return self
+                .levels_in(self.metamath_db.statements_range_address(addr..))
+                .find(|stmt| stmt.address() != addr)
+                .map(|stmt| stmt.address());
+        };
+        let gentle = |chapter: &[StatementAddress]| {
+            let mut levels = chapter
+                .iter()
+                .map(|&level| (self.difficulty(level).score(), level))
+                .collect::<Vec<_>>();
+            // Stable, so that levels as hard as each other keep the database order
+            levels.sort_by_key(|&(score, _)| score);
+            levels
+                .into_iter()
+                .map(|(_, level)| level)
+                .collect::<Vec<_>>()
+        };
+        let levels = gentle(&chapters.levels[chapter]);
+        let i = levels.iter().position(|&level| level == addr).unwrap();
+        let unsolved = |level: &&StatementAddress| **level != addr && !solved.contains(*level);
+        let harder = levels[i + 1..].iter().find(unsolved);
+        match harder.or_else(|| levels[..i].iter().find(unsolved)) {
+            Some(&next) => Some(next),
+            None => gentle(chapters.levels.get(chapter + 1)?).first().copied(),
+        }
+    }
+}
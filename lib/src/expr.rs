@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+
+use metamath_rs::{
+    formula::{Label, TypeCode},
+    statement::TokenPtr,
+    Database, Formula,
+};
+
+/// A node of a grammar-parsed expression
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Tree {
+    /// Database variable, identified by the label of its floating hypothesis
+    Var(Label),
+    /// Work variable, a placeholder that later unifications can refine
+    Work(TypeCode, u32),
+    /// Syntax axiom applied to its arguments, in order of first appearance
+    App(Label, Vec<Tree>),
+}
+
+/// A typecode together with a parse tree,
+/// which unlike `Formula` can contain work variables
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Expr {
+    pub typecode: TypeCode,
+    pub tree: Tree,
+}
+
+/// Assignments of work variables
+pub type WorkSubsts = HashMap<u32, Tree>;
+
+/// A math token of a flattened expression
+pub(crate) enum Token<'a> {
+    Symbol(TokenPtr<'a>),
+    Work(TypeCode, u32),
+}
+
+fn typecode_of_label(db: &Database, label: Label) -> TypeCode {
+    let stmt = db.statement_by_label(label).unwrap();
+    db.name_result().get_atom(&stmt.math_at(0))
+}
+
+/// A math token of a syntax axiom
+pub(crate) enum SyntaxPart<'a> {
+    Symbol(TokenPtr<'a>),
+    /// Variable standing for the argument with this index
+    Arg(usize),
+}
+
+/// Math tokens of a syntax axiom after its typecode
+pub(crate) fn syntax_parts(db: &Database, label: Label) -> Vec<SyntaxPart<'_>> {
+    let names = db.name_result();
+    let stmt = db.statement_by_label(label).unwrap();
+    let frame = db.scope_result().get(stmt.label()).unwrap();
+    let mut order = Vec::new();
+    stmt.math_iter()
+        .skip(1)
+        .map(|tok| {
+            let atom = names.get_atom(tok.slice);
+            if !frame.var_list.contains(&atom) {
+                return SyntaxPart::Symbol(tok.slice);
+            }
+            match order.iter().position(|&var| var == atom) {
+                Some(i) => SyntaxPart::Arg(i),
+                None => {
+                    order.push(atom);
+                    SyntaxPart::Arg(order.len() - 1)
+                }
+            }
+        })
+        .collect()
+}
+
+/// Number of distinct variables of a syntax axiom
+fn arity(db: &Database, label: Label) -> usize {
+    let stmt = db.statement_by_label(label).unwrap();
+    db.scope_result()
+        .get(stmt.label())
+        .map_or(0, |frame| frame.var_list.len())
+}
+
+impl Tree {
+    fn from_labels(db: &Database, labels: &mut impl Iterator<Item = (Label, bool)>) -> Self {
+        let (label, is_variable) = labels.next().unwrap();
+        if is_variable {
+            Self::Var(label)
+        } else {
+            let args = (0..arity(db, label))
+                .map(|_| Self::from_labels(db, labels))
+                .collect();
+            Self::App(label, args)
+        }
+    }
+
+    pub fn typecode(&self, db: &Database) -> TypeCode {
+        match self {
+            Self::Var(label) | Self::App(label, _) => typecode_of_label(db, *label),
+            Self::Work(typecode, _) => *typecode,
+        }
+    }
+
+    pub fn has_work_vars(&self) -> bool {
+        match self {
+            Self::Var(_) => false,
+            Self::Work(_, _) => true,
+            Self::App(_, args) => args.iter().any(Self::has_work_vars),
+        }
+    }
+
+    /// Highest number of the work variables
+    pub fn max_work_var(&self) -> Option<u32> {
+        match self {
+            Self::Var(_) => None,
+            Self::Work(_, n) => Some(*n),
+            Self::App(_, args) => args.iter().filter_map(Self::max_work_var).max(),
+        }
+    }
+
+    /// Replace every database variable, e.g. to instantiate a statement
+    pub fn map_vars(&self, f: &mut impl FnMut(Label) -> Self) -> Self {
+        match self {
+            Self::Var(label) => f(*label),
+            Self::Work(_, _) => self.clone(),
+            Self::App(label, args) => {
+                Self::App(*label, args.iter().map(|arg| arg.map_vars(f)).collect())
+            }
+        }
+    }
+
+    /// Visit every database variable
+    pub fn for_each_var(&self, f: &mut impl FnMut(Label)) {
+        match self {
+            Self::Var(label) => f(*label),
+            Self::Work(_, _) => {}
+            Self::App(_, args) => args.iter().for_each(|arg| arg.for_each_var(f)),
+        }
+    }
+
+    /// Apply assignments of work variables, following chains of assignments
+    pub fn substitute(&self, substs: &WorkSubsts) -> Self {
+        match self {
+            Self::Var(_) => self.clone(),
+            Self::Work(_, n) => match substs.get(n) {
+                Some(tree) => tree.substitute(substs),
+                None => self.clone(),
+            },
+            Self::App(label, args) => Self::App(
+                *label,
+                args.iter().map(|arg| arg.substitute(substs)).collect(),
+            ),
+        }
+    }
+
+    fn resolve<'a>(&'a self, substs: &'a WorkSubsts) -> &'a Self {
+        match self {
+            Self::Work(_, n) => match substs.get(n) {
+                Some(tree) => tree.resolve(substs),
+                None => self,
+            },
+            _ => self,
+        }
+    }
+
+    fn occurs(&self, n: u32, substs: &WorkSubsts) -> bool {
+        match self.resolve(substs) {
+            Self::Var(_) => false,
+            Self::Work(_, m) => *m == n,
+            Self::App(_, args) => args.iter().any(|arg| arg.occurs(n, substs)),
+        }
+    }
+
+    /// Unify two trees, extending `substs`.
+    /// Only work variables may be assigned, database variables are treated as constants.
+    pub fn unify(&self, other: &Self, db: &Database, substs: &mut WorkSubsts) -> bool {
+        let (typecode, n, value) = match (self.resolve(substs), other.resolve(substs)) {
+            (Self::Work(_, n), Self::Work(_, m)) if n == m => return true,
+            (Self::Work(typecode, n), value) | (value, Self::Work(typecode, n)) => {
+                (*typecode, *n, value.clone())
+            }
+            (Self::Var(a), Self::Var(b)) => return a == b,
+            (Self::App(a, a_args), Self::App(b, b_args)) => {
+                if a != b || a_args.len() != b_args.len() {
+                    return false;
+                }
+                let pairs = a_args
+                    .iter()
+                    .cloned()
+                    .zip(b_args.iter().cloned())
+                    .collect::<Vec<_>>();
+                return pairs.iter().all(|(a, b)| a.unify(b, db, substs));
+            }
+            _ => return false,
+        };
+        if value.typecode(db) != typecode || value.occurs(n, substs) {
+            return false;
+        }
+        substs.insert(n, value);
+        true
+    }
+
+    pub(crate) fn tokens<'a>(&self, db: &'a Database, out: &mut Vec<Token<'a>>) {
+        match self {
+            Self::Var(label) => {
+                let stmt = db.statement_by_label(*label).unwrap();
+                out.push(Token::Symbol(stmt.math_at(1).slice));
+            }
+            Self::Work(typecode, n) => out.push(Token::Work(*typecode, *n)),
+            Self::App(label, args) => {
+                for part in syntax_parts(db, *label) {
+                    match part {
+                        SyntaxPart::Symbol(tok) => out.push(Token::Symbol(tok)),
+                        SyntaxPart::Arg(i) => args[i].tokens(db, out),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Expr {
+    pub(crate) fn from_formula(db: &Database, formula: &Formula) -> Self {
+        Self {
+            typecode: formula.get_typecode(),
+            tree: Tree::from_labels(db, &mut formula.labels_iter()),
+        }
+    }
+
+    pub fn has_work_vars(&self) -> bool {
+        self.tree.has_work_vars()
+    }
+
+    pub fn substitute(&self, substs: &WorkSubsts) -> Self {
+        Self {
+            typecode: self.typecode,
+            tree: self.tree.substitute(substs),
+        }
+    }
+
+    /// Flatten into math tokens, starting with the typecode
+    pub(crate) fn tokens<'a>(&self, db: &'a Database) -> Vec<Token<'a>> {
+        let mut out = vec![Token::Symbol(db.name_result().atom_name(self.typecode))];
+        self.tree.tokens(db, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Context, TypesetMode};
+
+    /// Propositional calculus with a second typecode
+    const DB: &str = "
+        $( $j syntax 'wff'; syntax 'class'; syntax '|-' as 'wff'; $)
+        $c ( ) -> -. wff class |- $.
+        $v ph ps ch A $.
+        wph $f wff ph $.
+        wps $f wff ps $.
+        wch $f wff ch $.
+        cA $f class A $.
+        wn $a wff -. ph $.
+        wi $a wff ( ph -> ps ) $.
+        ax-1 $a |- ( ph -> ( ps -> ph ) ) $.
+        ax-2 $a |- ( ( ph -> ( ps -> ch ) ) -> ( ( ph -> ps ) -> ( ph -> ch ) ) ) $.
+        ax-3 $a |- ( ( -. ph -> -. ps ) -> ( ps -> ph ) ) $.
+        ${
+            $d ph ps $.
+            ax-dv $a |- ( ph -> ( ps -> ph ) ) $.
+        $}
+        ${
+            min $e |- ph $.
+            maj $e |- ( ph -> ps ) $.
+            ax-mp $a |- ps $.
+        $}
+        a1 $p |- ( ch -> ( ph -> ch ) ) $= ? $.
+        a1c $p |- ( ch -> ( ch -> ph ) ) $= ? $.
+        notnotr $p |- ( -. -. ph -> ph ) $= ? $.
+        ${
+            syl.1 $e |- ( ph -> ps ) $.
+            syl.2 $e |- ( ps -> ch ) $.
+            syl $p |- ( ph -> ch ) $= ? $.
+        $}
+        ${
+            a1i.1 $e |- ph $.
+            a1i $p |- ( ps -> ph ) $= ? $.
+        $}
+        ${
+            mp1.1 $e |- ph $.
+            mp1.2 $e |- ( ph -> ps ) $.
+            mp1 $p |- ps $= ? $.
+        $}
+        ${
+            mp2.1 $e |- ch $.
+            mp2.2 $e |- ( ch -> ph ) $.
+            mp2 $p |- ph $= ? $.
+        $}
+        ${
+            $d ch ph $.
+            a1dv $p |- ( ch -> ( ph -> ch ) ) $= ? $.
+        $}
+    ";
+
+    pub(crate) fn context() -> Context {
+        Context::load("test.mm", DB, TypesetMode::AltHtml)
+    }
+
+    fn label(ctx: &Context, name: &str) -> Label {
+        ctx.metamath_db.name_result().get_atom(name.as_bytes())
+    }
+
+    fn wff(ctx: &Context, n: u32) -> Tree {
+        Tree::Work(label(ctx, "wff"), n)
+    }
+
+    fn imp(ctx: &Context, a: Tree, b: Tree) -> Tree {
+        Tree::App(label(ctx, "wi"), vec![a, b])
+    }
+
+    #[test]
+    fn unify_occurs_check() {
+        let ctx = context();
+        let db = &ctx.metamath_db;
+        let ph = Tree::Var(label(&ctx, "wph"));
+        let mut substs = WorkSubsts::new();
+        assert!(!wff(&ctx, 0).unify(&imp(&ctx, wff(&ctx, 0), ph.clone()), db, &mut substs));
+        // Also through an assignment
+        let mut substs = WorkSubsts::new();
+        assert!(wff(&ctx, 0).unify(&wff(&ctx, 1), db, &mut substs));
+        assert!(!wff(&ctx, 1).unify(&imp(&ctx, ph, wff(&ctx, 0)), db, &mut substs));
+    }
+
+    #[test]
+    fn unify_typecode_mismatch() {
+        let ctx = context();
+        let db = &ctx.metamath_db;
+        let class = Tree::Var(label(&ctx, "cA"));
+        let mut substs = WorkSubsts::new();
+        assert!(!wff(&ctx, 0).unify(&class, db, &mut substs));
+        let class_work = Tree::Work(label(&ctx, "class"), 1);
+        assert!(!class_work.unify(&wff(&ctx, 0), db, &mut substs));
+        assert!(substs.is_empty());
+    }
+
+    #[test]
+    fn unify_chained_assignments() {
+        let ctx = context();
+        let db = &ctx.metamath_db;
+        let ph = Tree::Var(label(&ctx, "wph"));
+        let ps = Tree::Var(label(&ctx, "wps"));
+        let mut substs = WorkSubsts::new();
+        assert!(wff(&ctx, 0).unify(&wff(&ctx, 1), db, &mut substs));
+        assert!(wff(&ctx, 1).unify(&wff(&ctx, 2), db, &mut substs));
+        assert!(wff(&ctx, 2).unify(&imp(&ctx, ph.clone(), ps.clone()), db, &mut substs));
+        assert_eq!(
+            wff(&ctx, 0).substitute(&substs),
+            imp(&ctx, ph.clone(), ps.clone())
+        );
+        // The assignment is seen through the chain
+        assert!(imp(&ctx, wff(&ctx, 0), wff(&ctx, 3)).unify(
+            &imp(&ctx, imp(&ctx, ph.clone(), ps.clone()), ph.clone()),
+            db,
+            &mut substs
+        ));
+        assert!(!wff(&ctx, 0).unify(&ph, db, &mut substs));
+        assert_eq!(wff(&ctx, 3).substitute(&substs), ph);
+    }
+
+    #[test]
+    fn unify_treats_database_variables_as_constants() {
+        let ctx = context();
+        let db = &ctx.metamath_db;
+        let ph = Tree::Var(label(&ctx, "wph"));
+        let ps = Tree::Var(label(&ctx, "wps"));
+        assert!(!ph.unify(&ps, db, &mut WorkSubsts::new()));
+        assert!(ph.unify(&ph, db, &mut WorkSubsts::new()));
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+};
+
+use serde::Serialize;
+
+use crate::{Context, Progress, StatementAddress};
+
+/// Levels shown on the map before and after the current one, without a manifest
+const MAP_RADIUS: usize = 20;
+
+/// How a level appears on the map
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeStatus {
+    Solved,
+    /// Every level of the graph it uses is solved
+    Available,
+    Locked,
+}
+
+/// A level of a dependency graph
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphNode {
+    #[serde(skip)]
+    pub addr: StatementAddress,
+    pub label: String,
+    /// Whether the player has finished the level
+    pub solved: bool,
+}
+
+/// A use of a level in the reference proof of another
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct GraphEdge {
+    /// Index of the node used
+    pub from: usize,
+    /// Index of the node whose proof uses it
+    pub to: usize,
+}
+
+/// Levels with the uses between them in their reference proofs
+#[derive(Clone, Debug, Serialize)]
+pub struct DepGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl DepGraph {
+    /// Graphviz graph with solved levels filled
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph levels {\n");
+        for node in &self.nodes {
+            let style = if node.solved {
+                " style=filled fillcolor=palegreen"
+            } else {
+                ""
+            };
+            writeln!(out, "  \"{}\" [shape=box{style}];", node.label).unwrap();
+        }
+        for edge in &self.edges {
+            writeln!(
+                out,
+                "  \"{}\" -> \"{}\";",
+                self.nodes[edge.from].label, self.nodes[edge.to].label
+            )
+            .unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn status(&self, i: usize) -> NodeStatus {
+        if self.nodes[i].solved {
+            NodeStatus::Solved
+        } else if self
+            .edges
+            .iter()
+            .all(|edge| edge.to != i || self.nodes[edge.from].solved)
+        {
+            NodeStatus::Available
+        } else {
+            NodeStatus::Locked
+        }
+    }
+
+    /// Length of the longest chain of uses leading to each node, for drawing the graph in layers
+    pub fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![0; self.nodes.len()];
+        let mut num_uses = vec![0; self.nodes.len()];
+        for edge in &self.edges {
+            num_uses[edge.to] += 1;
+        }
+        let mut ready = (0..self.nodes.len())
+            .filter(|&i| num_uses[i] == 0)
+            .collect::<VecDeque<_>>();
+        while let Some(i) = ready.pop_front() {
+            for edge in self.edges.iter().filter(|edge| edge.from == i) {
+                depths[edge.to] = depths[edge.to].max(depths[i] + 1);
+                num_uses[edge.to] -= 1;
+                if num_uses[edge.to] == 0 {
+                    ready.push_back(edge.to);
+                }
+            }
+        }
+        depths
+    }
+
+    /// Column and row of each node, drawn in columns of nodes of equal depth
+    pub fn grid_positions(&self) -> Vec<(usize, usize)> {
+        let depths = self.depths();
+        let mut column_sizes = vec![0; self.nodes.len()];
+        depths
+            .into_iter()
+            .map(|depth| {
+                column_sizes[depth] += 1;
+                (depth, column_sizes[depth] - 1)
+            })
+            .collect()
+    }
+}
+
+impl Context {
+    /// Levels of the manifest, or else the levels around `current`
+    pub fn map_levels(&self, current: StatementAddress) -> Vec<StatementAddress> {
+        if let Some(pack) = &self.level_pack {
+            return pack.levels().to_vec();
+        }
+        let mut levels = self.levels_in(self.metamath_db.statements());
+        let mut before = VecDeque::new();
+        let mut found = false;
+        for stmt in levels.by_ref() {
+            if stmt.address() == current {
+                found = true;
+                break;
+            }
+            if before.len() == MAP_RADIUS {
+                before.pop_front();
+            }
+            before.push_back(stmt.address());
+        }
+        // The current level may be one the level filter skips
+        if !found {
+            return vec![current];
+        }
+        before
+            .into_iter()
+            .chain([current])
+            .chain(levels.take(MAP_RADIUS).map(|stmt| stmt.address()))
+            .collect()
+    }
+
+    /// Graph of `levels`, with an edge for each use of one of them in the proof of another
+    pub fn dep_graph(&self, levels: &[StatementAddress], progress: &Progress) -> DepGraph {
+        let nodes = levels
+            .iter()
+            .map(|&addr| {
+                let label = self.label(addr);
+                GraphNode {
+                    addr,
+                    solved: progress.solved.contains(&label),
+                    label,
+                }
+            })
+            .collect::<Vec<_>>();
+        let indices = levels
+            .iter()
+            .enumerate()
+            .map(|(i, &addr)| (addr, i))
+            .collect::<HashMap<_, _>>();
+        let edges = levels
+            .iter()
+            .enumerate()
+            .flat_map(|(to, &addr)| {
+                self.deps(addr)
+                    .into_iter()
+                    .filter_map(|dep| indices.get(&dep).copied())
+                    .filter(move |&from| from != to)
+                    .map(move |from| GraphEdge { from, to })
+            })
+            .collect();
+        DepGraph { nodes, edges }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DepGraph, NodeStatus};
+    use crate::{Context, Progress, TypesetMode};
+
+    /// Three levels, each proven with the previous one
+    const DB: &str = "
+        $( $j syntax 'wff'; syntax '|-' as 'wff'; $)
+        $c ( ) -> wff |- $.
+        $v ph ps $.
+        wph $f wff ph $.
+        wps $f wff ps $.
+        wi $a wff ( ph -> ps ) $.
+        ax-1 $a |- ( ph -> ( ps -> ph ) ) $.
+        ${
+            min $e |- ph $.
+            maj $e |- ( ph -> ps ) $.
+            ax-mp $a |- ps $.
+        $}
+        th1 $p |- ( ph -> ( ps -> ph ) ) $= wph wps ax-1 $.
+        th2 $p |- ( ph -> ( ph -> ph ) ) $= wph wph th1 $.
+        ${
+            th3.1 $e |- ph $.
+            th3 $p |- ( ph -> ph ) $= wph wph wph wi th3.1 wph th2 ax-mp $.
+        $}
+    ";
+
+    /// The chain with its first level solved
+    fn graph() -> DepGraph {
+        let ctx = Context::load("test.mm", DB, TypesetMode::AltHtml);
+        let progress = Progress {
+            solved: vec!["th1".into()],
+            ..Default::default()
+        };
+        ctx.dep_graph(&ctx.parse_levels("th1..th3").unwrap(), &progress)
+    }
+
+    #[test]
+    fn statuses_and_depths_follow_the_chain() {
+        let graph = graph();
+        assert_eq!(graph.status(0), NodeStatus::Solved);
+        // Its only dependency is solved
+        assert_eq!(graph.status(1), NodeStatus::Available);
+        assert_eq!(graph.status(2), NodeStatus::Locked);
+        assert_eq!(graph.depths(), [0, 1, 2]);
+        assert_eq!(graph.grid_positions(), [(0, 0), (1, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn dot_output() {
+        assert_eq!(
+            graph().to_dot(),
+            "digraph levels {
+  \"th1\" [shape=box style=filled fillcolor=palegreen];
+  \"th2\" [shape=box];
+  \"th3\" [shape=box];
+  \"th1\" -> \"th2\";
+  \"th2\" -> \"th3\";
+}
+"
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let json = serde_json::from_str::<serde_json::Value>(&graph().to_json()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "nodes": [
+                    { "label": "th1", "solved": true },
+                    { "label": "th2", "solved": false },
+                    { "label": "th3", "solved": false },
+                ],
+                "edges": [{ "from": 0, "to": 1 }, { "from": 1, "to": 2 }],
+            })
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use metamath_rs::{
+    formula::Label,
+    scopeck::{Frame, Hyp},
+    statement::StatementAddress,
+    Database, StatementType,
+};
+
+use crate::{Context, Expr, Lemma, Rule, Step, Tree, WorkSubsts};
+
+/// Parsed statements of the database, so that pushing and rendering buttons
+/// need no grammar parsing
+#[derive(Default)]
+pub(crate) struct StatementIndex {
+    /// Every assertion and hypothesis the grammar parses
+    exprs: HashMap<StatementAddress, Expr>,
+    /// Every assertion whose hypotheses and conclusion are parsed
+    rules: HashMap<StatementAddress, Rule>,
+    /// Variables and disjoint variable conditions of every assertion
+    frames: HashMap<StatementAddress, FrameVars>,
+}
+
+/// Mandatory variables of an assertion, each identified by the label of its floating
+/// hypothesis, with the pairs of them its `$d` statements keep apart
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FrameVars {
+    pub(crate) vars: Vec<Label>,
+    /// Indices into `vars`
+    pub(crate) dvs: Vec<(usize, usize)>,
+}
+
+impl StatementIndex {
+    pub(crate) fn new(ctx: &Context) -> Self {
+        let db = &ctx.metamath_db;
+        let exprs = db
+            .statements()
+            .filter(|stmt| {
+                matches!(
+                    stmt.statement_type(),
+                    StatementType::Axiom
+                        | StatementType::Provable
+                        | StatementType::Essential
+                        | StatementType::Floating
+                )
+            })
+            .filter_map(|stmt| Some((stmt.address(), ctx.stmt_to_expr(stmt)?)))
+            .collect::<HashMap<_, _>>();
+        let scopes = db.scope_result();
+        let rules = db
+            .statements()
+            .filter_map(|stmt| {
+                let frame = scopes.get(stmt.label())?;
+                let hyps = frame
+                    .hypotheses
+                    .iter()
+                    .map(|hyp| {
+                        let expr = exprs.get(&hyp.address())?.clone();
+                        Some((matches!(hyp, Hyp::Essential(_, _)), expr))
+                    })
+                    .collect::<Option<_>>()?;
+                let rule = Rule {
+                    hyps,
+                    conclusion: exprs.get(&stmt.address())?.clone(),
+                };
+                Some((stmt.address(), rule))
+            })
+            .collect();
+        let frames = db
+            .statements()
+            .filter_map(|stmt| {
+                let frame = scopes.get(stmt.label())?;
+                Some((stmt.address(), frame_vars(db, frame)?))
+            })
+            .collect();
+        Self {
+            exprs,
+            rules,
+            frames,
+        }
+    }
+
+    pub(crate) fn expr(&self, addr: StatementAddress) -> Option<&Expr> {
+        self.exprs.get(&addr)
+    }
+
+    pub(crate) fn rule(&self, addr: StatementAddress) -> Option<&Rule> {
+        self.rules.get(&addr)
+    }
+
+    pub(crate) fn frame(&self, addr: StatementAddress) -> Option<&FrameVars> {
+        self.frames.get(&addr)
+    }
+}
+
+/// Mandatory variables of a frame and its `$d` pairs among them,
+/// `None` if a variable has no floating hypothesis
+fn frame_vars(db: &Database, frame: &Frame) -> Option<FrameVars> {
+    let names = db.name_result();
+    let labels = frame
+        .hypotheses
+        .iter()
+        .filter_map(|hyp| match hyp {
+            Hyp::Floating(addr, index, _) => {
+                let label = names.get_atom(db.statement_by_address(*addr).label());
+                Some((*index, label))
+            }
+            Hyp::Essential(_, _) => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let vars = (0..frame.mandatory_count)
+        .map(|index| labels.get(&index).copied())
+        .collect::<Option<Vec<_>>>()?;
+    let dvs = frame
+        .mandatory_dv
+        .iter()
+        .copied()
+        .filter(|&(a, b)| a < vars.len() && b < vars.len())
+        .collect();
+    Some(FrameVars { vars, dvs })
+}
+
+/// Pairs of values with the work variables assigned by `substs` replaced
+pub(crate) fn substitute_dvs(pairs: &[(Tree, Tree)], substs: &WorkSubsts) -> Vec<(Tree, Tree)> {
+    pairs
+        .iter()
+        .map(|(a, b)| (a.substitute(substs), b.substitute(substs)))
+        .collect()
+}
+
+/// Whether work variables are left in a pair of values that a `$d` keeps apart,
+/// which could end up sharing a variable with the other value
+pub(crate) fn dv_unresolved(a: &Tree, b: &Tree) -> bool {
+    let has_vars = |tree: &Tree| {
+        let mut any = tree.has_work_vars();
+        tree.for_each_var(&mut |_| any = true);
+        any
+    };
+    (a.has_work_vars() && has_vars(b)) || (b.has_work_vars() && has_vars(a))
+}
+
+impl Context {
+    /// Pairs of variables the `$d`s of a step keep apart
+    pub(crate) fn step_dvs(&self, step: Step, lemmas: &[Lemma]) -> Vec<(Label, Label)> {
+        match step {
+            Step::Stmt(addr) => self
+                .statement_index
+                .frame(addr)
+                .map_or_else(Vec::new, |frame| {
+                    frame
+                        .dvs
+                        .iter()
+                        .map(|&(a, b)| (frame.vars[a], frame.vars[b]))
+                        .collect()
+                }),
+            Step::Lemma(i) => lemmas
+                .get(i)
+                .map_or_else(Vec::new, |lemma| lemma.dvs.clone()),
+        }
+    }
+
+    /// Values of the pairs of variables the `$d`s of a step keep apart,
+    /// with `vars` assigning its variables
+    pub(crate) fn dv_values(
+        &self,
+        step: Step,
+        lemmas: &[Lemma],
+        vars: &[(Label, Tree)],
+    ) -> Vec<(Tree, Tree)> {
+        let value = |label: Label| {
+            vars.iter()
+                .find(|(var, _)| *var == label)
+                .map_or(Tree::Var(label), |(_, tree)| tree.clone())
+        };
+        self.step_dvs(step, lemmas)
+            .into_iter()
+            .map(|(a, b)| (value(a), value(b)))
+            .collect()
+    }
+
+    /// Whether the `$d`s in scope of `level` keep apart the variables of each pair of values.
+    /// Work variables are skipped until they are assigned.
+    /// Without a level, as in sandbox mode, no variables are kept apart.
+    pub(crate) fn dvs_hold(&self, level: Option<StatementAddress>, pairs: &[(Tree, Tree)]) -> bool {
+        pairs.iter().all(|(a, b)| {
+            let mut vars_b = Vec::new();
+            b.for_each_var(&mut |var| vars_b.push(var));
+            let mut holds = true;
+            a.for_each_var(&mut |x| {
+                holds &= vars_b
+                    .iter()
+                    .all(|&y| level.is_some_and(|level| self.level_keeps_apart(level, x, y)))
+            });
+            holds
+        })
+    }
+
+    /// Whether distinct variables `x` and `y` have a `$d` in scope of `level`,
+    /// including those on variables its statement does not mention
+    fn level_keeps_apart(&self, level: StatementAddress, x: Label, y: Label) -> bool {
+        if x == y {
+            return false;
+        }
+        let db = &self.metamath_db;
+        let names = db.name_result();
+        let Some(frame) = db
+            .scope_result()
+            .get(db.statement_by_address(level).label())
+        else {
+            return false;
+        };
+        // Variables are identified by their floating hypotheses, the frame uses their symbols
+        let index = |var: Label| -> Option<usize> {
+            let symbol = names.get_atom(db.statement_by_label(var)?.math_at(1).slice);
+            frame.var_list.iter().position(|&other| other == symbol)
+        };
+        match (index(x), index(y)) {
+            (Some(i), Some(j)) => frame.optional_dv[i].has_bit(j),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{expr::tests::context, Action, Context, State, Step};
+
+    fn push_all(ctx: &Context, level: &str, labels: &[&str]) -> State {
+        let actions = labels
+            .iter()
+            .map(|label| {
+                let addr = ctx
+                    .metamath_db
+                    .statement(label.as_bytes())
+                    .unwrap()
+                    .address();
+                Action::Push(Step::Stmt(addr))
+            })
+            .collect::<Vec<_>>();
+        ctx.initial_state(Some(level))
+            .apply_all(ctx, &[], &actions)
+            .unwrap()
+    }
+
+    #[test]
+    fn pushes_keep_disjoint_vars_apart() {
+        let ctx = context();
+        let stack_len = |level, labels: &[&str]| push_all(&ctx, level, labels).proof_stack.len();
+        // a1 has no `$d ch ph`, so ax-dv takes only one of the entries
+        assert_eq!(stack_len("a1", &["wch", "wph", "ax-dv"]), 2);
+        assert_eq!(stack_len("a1dv", &["wch", "wph", "ax-dv"]), 1);
+        // Substituting the same variable for both consumes only one of the entries
+        assert_eq!(stack_len("a1dv", &["wch", "wch", "ax-dv"]), 2);
+    }
+
+    #[test]
+    fn finishing_checks_disjoint_vars_of_assigned_work_vars() {
+        let ctx = context();
+        // The goal assigns the work variables ax-dv is pushed with
+        assert!(!push_all(&ctx, "a1", &["ax-dv"]).level_finished(&ctx));
+        assert!(push_all(&ctx, "a1dv", &["ax-dv"]).level_finished(&ctx));
+    }
+}
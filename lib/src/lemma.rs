@@ -0,0 +1,464 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use itertools::Itertools;
+use metamath_rs::{
+    formula::{Label, TypeCode},
+    StatementType,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    from_utf8, index, Context, Expr, Progress, Proof, Rule, StatementAddress, Step, Tree,
+    WorkSubsts,
+};
+
+/// A statement proven by the player, usable as a step in later levels
+#[derive(Clone, Debug)]
+pub struct Lemma {
+    pub name: String,
+
+    /// Hypotheses of the level the lemma was proven in, which its proof uses
+    hyp_addrs: Vec<StatementAddress>,
+
+    pub(crate) rule: Rule,
+
+    /// Pairs of variables its proof needs kept apart, enforced wherever it is used
+    pub(crate) dvs: Vec<(Label, Label)>,
+
+    proof: Arc<Proof>,
+}
+
+/// A lemma as kept in the player's progress
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedLemma {
+    pub name: String,
+
+    /// Labels of the hypotheses
+    pub hyps: Vec<String>,
+
+    /// Math tokens of the conclusion
+    pub conclusion: String,
+
+    pub proof: SavedProof,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedProof {
+    /// Label of a database statement, or name of an earlier lemma
+    pub step: String,
+
+    pub hyps: Vec<SavedProof>,
+}
+
+impl Lemma {
+    /// `None` if a `$d` of its proof applies to work variables left unassigned,
+    /// which the exported proof could not satisfy
+    pub(crate) fn new(
+        ctx: &Context,
+        lemmas: &[Lemma],
+        name: String,
+        hyp_addrs: Vec<StatementAddress>,
+        conclusion: Expr,
+        proof: Arc<Proof>,
+    ) -> Option<Self> {
+        let hyps = hyp_addrs
+            .iter()
+            .map(|&addr| Some((true, ctx.stmt_expr(addr)?.clone())))
+            .collect::<Option<_>>()?;
+        let resolved = ctx.resolve_proof(&proof, &conclusion, lemmas)?;
+        let mut values = Vec::new();
+        ctx.resolved_dvs(&resolved, lemmas, &mut values);
+        if values.iter().any(|(a, b)| index::dv_unresolved(a, b)) {
+            return None;
+        }
+        let mut dvs = Vec::new();
+        for (a, b) in &values {
+            a.for_each_var(&mut |x| b.for_each_var(&mut |y| dvs.push((x, y))));
+        }
+        Some(Self {
+            name,
+            hyp_addrs,
+            rule: Rule { hyps, conclusion },
+            dvs: dvs.into_iter().unique().collect(),
+            proof,
+        })
+    }
+}
+
+impl Context {
+    pub fn load_lemmas(&self, progress: &Progress) -> Vec<Lemma> {
+        let mut lemmas = Vec::new();
+        // Lemmas which no longer fit the database are dropped, along with those using them
+        for saved in &progress.lemmas {
+            if let Some(lemma) = self.load_lemma(saved, &lemmas) {
+                lemmas.push(lemma);
+            }
+        }
+        lemmas
+    }
+
+    fn load_lemma(&self, saved: &SavedLemma, lemmas: &[Lemma]) -> Option<Lemma> {
+        let db = &self.metamath_db;
+        let hyp_addrs = saved
+            .hyps
+            .iter()
+            .map(|label| Some(db.statement(label.as_bytes())?.address()))
+            .collect::<Option<_>>()?;
+        let conclusion = self.parse_expr(&saved.conclusion).ok()?;
+        let proof = self.load_proof(&saved.proof, lemmas)?;
+        Lemma::new(
+            self,
+            lemmas,
+            saved.name.clone(),
+            hyp_addrs,
+            conclusion,
+            proof,
+        )
+    }
+
+    fn load_proof(&self, saved: &SavedProof, lemmas: &[Lemma]) -> Option<Arc<Proof>> {
+        let step = self.step_by_label(&saved.step, lemmas)?;
+        let hyps = saved
+            .hyps
+            .iter()
+            .map(|hyp| self.load_proof(hyp, lemmas))
+            .collect::<Option<_>>()?;
+        Some(Arc::new(Proof { step, hyps }))
+    }
+
+    pub fn save_lemma(&self, lemma: &Lemma, lemmas: &[Lemma]) -> SavedLemma {
+        SavedLemma {
+            name: lemma.name.clone(),
+            hyps: lemma
+                .hyp_addrs
+                .iter()
+                .map(|&addr| self.label(addr))
+                .collect(),
+            conclusion: self.expr_to_ascii(&lemma.rule.conclusion),
+            proof: self.save_proof(&lemma.proof, lemmas),
+        }
+    }
+
+    fn save_proof(&self, proof: &Proof, lemmas: &[Lemma]) -> SavedProof {
+        SavedProof {
+            step: self.step_label(proof.step, lemmas),
+            hyps: proof
+                .hyps
+                .iter()
+                .map(|hyp| self.save_proof(hyp, lemmas))
+                .collect(),
+        }
+    }
+
+    /// Labels of a proof of `conclusion` in reverse Polish notation,
+    /// with lemmas expanded and the syntax proofs of every variable
+    pub fn export_proof(
+        &self,
+        proof: &Proof,
+        conclusion: &Expr,
+        lemmas: &[Lemma],
+    ) -> Option<Vec<String>> {
+        let resolved = self.resolve_proof(proof, conclusion, lemmas)?;
+        let mut used = HashSet::new();
+        conclusion.tree.for_each_var(&mut |var| {
+            used.insert(var);
+        });
+        self.proof_vars(&resolved, lemmas, &mut used)?;
+        let dummies = self.dummy_vars(&used);
+        let mut out = Vec::new();
+        self.expand_proof(
+            &resolved,
+            lemmas,
+            &HashMap::new(),
+            &HashMap::new(),
+            &dummies,
+            &mut out,
+        )?;
+        Some(out)
+    }
+
+    /// Values the `$d`s of every step of a resolved proof keep apart
+    fn resolved_dvs(&self, proof: &Resolved, lemmas: &[Lemma], out: &mut Vec<(Tree, Tree)>) {
+        out.extend(self.dv_values(proof.step, lemmas, &proof.vars));
+        for hyp in &proof.hyps {
+            self.resolved_dvs(hyp, lemmas, out);
+        }
+    }
+
+    /// Variables of a resolved proof, including those inside the proofs of its lemmas
+    fn proof_vars(
+        &self,
+        proof: &Resolved,
+        lemmas: &[Lemma],
+        out: &mut HashSet<Label>,
+    ) -> Option<()> {
+        for (_, tree) in &proof.vars {
+            tree.for_each_var(&mut |var| {
+                out.insert(var);
+            });
+        }
+        if let Step::Lemma(i) = proof.step {
+            let lemma = lemmas.get(i)?;
+            let lemma_proof = self.resolve_proof(&lemma.proof, &lemma.rule.conclusion, lemmas)?;
+            self.proof_vars(&lemma_proof, lemmas, out)?;
+        }
+        for hyp in &proof.hyps {
+            self.proof_vars(hyp, lemmas, out)?;
+        }
+        Some(())
+    }
+
+    /// Replay the unifications of a proof, ending with its conclusion,
+    /// to find the value of every variable of its steps
+    fn resolve_proof(
+        &self,
+        proof: &Proof,
+        conclusion: &Expr,
+        lemmas: &[Lemma],
+    ) -> Option<Resolved> {
+        let mut next_work_var = conclusion.tree.max_work_var().map_or(0, |n| n + 1);
+        let mut substs = WorkSubsts::new();
+        let (mut resolved, expr) =
+            self.replay_proof(proof, lemmas, &mut next_work_var, &mut substs)?;
+        if expr.typecode != conclusion.typecode
+            || !expr
+                .tree
+                .unify(&conclusion.tree, &self.metamath_db, &mut substs)
+        {
+            return None;
+        }
+        resolved.substitute(&substs);
+        Some(resolved)
+    }
+
+    fn replay_proof(
+        &self,
+        proof: &Proof,
+        lemmas: &[Lemma],
+        next_work_var: &mut u32,
+        substs: &mut WorkSubsts,
+    ) -> Option<(Resolved, Expr)> {
+        let db = &self.metamath_db;
+        let Some(rule) = self.step_rule(proof.step, lemmas) else {
+            // Hypotheses of the level have no variables
+            let Step::Stmt(addr) = proof.step else {
+                return None;
+            };
+            let resolved = Resolved {
+                step: proof.step,
+                vars: Vec::new(),
+                hyps: Vec::new(),
+            };
+            return Some((resolved, self.stmt_expr(addr)?.clone()));
+        };
+        let (rule, vars) = rule.instantiate_vars(db, next_work_var);
+        let mut proof_hyps = proof.hyps.iter();
+        let mut hyps = Vec::new();
+        for (_, hyp) in rule.hyps.iter().filter(|(essential, _)| *essential) {
+            let (resolved, expr) =
+                self.replay_proof(proof_hyps.next()?, lemmas, next_work_var, substs)?;
+            if expr.typecode != hyp.typecode || !expr.tree.unify(&hyp.tree, db, substs) {
+                return None;
+            }
+            hyps.push(resolved);
+        }
+        let resolved = Resolved {
+            step: proof.step,
+            vars,
+            hyps,
+        };
+        Some((resolved, rule.conclusion))
+    }
+
+    /// `var_values` assigns the variables of the lemma currently being expanded,
+    /// and `hyp_proofs` holds the expanded proofs standing in for its hypotheses
+    /// `dummies` stand for unassigned work variables.
+    fn expand_proof(
+        &self,
+        proof: &Resolved,
+        lemmas: &[Lemma],
+        var_values: &HashMap<Label, Tree>,
+        hyp_proofs: &HashMap<StatementAddress, Vec<String>>,
+        dummies: &HashMap<TypeCode, String>,
+        out: &mut Vec<String>,
+    ) -> Option<()> {
+        let value = |tree: &Tree| {
+            tree.map_vars(&mut |label| var_values.get(&label).cloned().unwrap_or(Tree::Var(label)))
+        };
+        match proof.step {
+            Step::Stmt(addr) => {
+                if let Some(hyp_proof) = hyp_proofs.get(&addr) {
+                    out.extend(hyp_proof.iter().cloned());
+                    return Some(());
+                }
+                if let Some(rule) = self.rule(addr) {
+                    let mut hyps = proof.hyps.iter();
+                    for (essential, hyp) in &rule.hyps {
+                        if *essential {
+                            let hyp = hyps.next()?;
+                            self.expand_proof(hyp, lemmas, var_values, hyp_proofs, dummies, out)?;
+                        } else {
+                            let (_, tree) = proof.vars.iter().find(
+                                |(label, _)| matches!(hyp.tree, Tree::Var(var) if var == *label),
+                            )?;
+                            self.syntax_proof(&value(tree), dummies, out);
+                        }
+                    }
+                }
+                out.push(self.label(addr));
+            }
+            Step::Lemma(i) => {
+                let lemma = lemmas.get(i)?;
+                let lemma_hyp_proofs = lemma
+                    .hyp_addrs
+                    .iter()
+                    .zip(&proof.hyps)
+                    .map(|(&addr, hyp)| {
+                        let mut hyp_proof = Vec::new();
+                        self.expand_proof(
+                            hyp,
+                            lemmas,
+                            var_values,
+                            hyp_proofs,
+                            dummies,
+                            &mut hyp_proof,
+                        )?;
+                        Some((addr, hyp_proof))
+                    })
+                    .collect::<Option<_>>()?;
+                let lemma_var_values = proof
+                    .vars
+                    .iter()
+                    .map(|(label, tree)| (*label, value(tree)))
+                    .collect();
+                let lemma_proof =
+                    self.resolve_proof(&lemma.proof, &lemma.rule.conclusion, lemmas)?;
+                self.expand_proof(
+                    &lemma_proof,
+                    lemmas,
+                    &lemma_var_values,
+                    &lemma_hyp_proofs,
+                    dummies,
+                    out,
+                )?;
+            }
+        }
+        Some(())
+    }
+
+    /// Proof of a syntax tree, with a dummy variable for each unassigned work variable
+    fn syntax_proof(
+        &self,
+        tree: &Tree,
+        dummies: &HashMap<TypeCode, String>,
+        out: &mut Vec<String>,
+    ) {
+        let db = &self.metamath_db;
+        match tree {
+            Tree::Var(label) => out.push(from_utf8(db.statement_by_label(*label).unwrap().label())),
+            Tree::Work(typecode, _) => out.push(dummies.get(typecode).cloned().unwrap_or_default()),
+            Tree::App(label, args) => {
+                let stmt = db.statement_by_label(*label).unwrap();
+                let rule = self.rule(stmt.address()).unwrap();
+                // The syntax axiom applied to its own variables, in the order of the arguments
+                let Tree::App(_, vars) = &rule.conclusion.tree else {
+                    unreachable!("a syntax axiom parses as itself")
+                };
+                for (_, hyp) in &rule.hyps {
+                    let i = vars.iter().position(|var| *var == hyp.tree).unwrap();
+                    self.syntax_proof(&args[i], dummies, out);
+                }
+                out.push(from_utf8(stmt.label()));
+            }
+        }
+    }
+
+    /// Label of the first floating hypothesis of each typecode whose variable is not `used`
+    fn dummy_vars(&self, used: &HashSet<Label>) -> HashMap<TypeCode, String> {
+        let db = &self.metamath_db;
+        let names = db.name_result();
+        let mut dummies = HashMap::new();
+        for stmt in db.statements() {
+            if stmt.statement_type() == StatementType::Floating
+                && !used.contains(&names.get_atom(stmt.label()))
+            {
+                dummies
+                    .entry(names.get_atom(stmt.math_at(0).slice))
+                    .or_insert_with(|| from_utf8(stmt.label()));
+            }
+        }
+        dummies
+    }
+}
+
+/// A proof with the values of the variables of its steps
+struct Resolved {
+    step: Step,
+    vars: Vec<(Label, Tree)>,
+    hyps: Vec<Resolved>,
+}
+
+impl Resolved {
+    fn substitute(&mut self, substs: &WorkSubsts) {
+        for (_, tree) in &mut self.vars {
+            *tree = tree.substitute(substs);
+        }
+        for hyp in &mut self.hyps {
+            hyp.substitute(substs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{expr::tests::context, Action, Context, Lemma, State, Step};
+
+    fn play(ctx: &Context, level: &str, lemmas: &[Lemma], steps: &[&str]) -> State {
+        steps
+            .iter()
+            .fold(ctx.initial_state(Some(level)), |state, label| {
+                let step = ctx.step_by_label(label, lemmas).unwrap();
+                state.apply(ctx, lemmas, Action::Push(step)).unwrap()
+            })
+    }
+
+    #[test]
+    fn export_emits_syntax_proofs() {
+        let ctx = context();
+        let state = play(&ctx, "a1", &[], &["ax-1"]);
+        assert_eq!(state.export_proof(&ctx, &[]).unwrap(), "wch wph ax-1");
+        let state = play(&ctx, "a1i", &[], &["a1i.1", "ax-1", "ax-mp"]);
+        assert_eq!(
+            state.export_proof(&ctx, &[]).unwrap(),
+            "wph wps wph wi a1i.1 wph wps ax-1 ax-mp"
+        );
+    }
+
+    #[test]
+    fn export_substitutes_lemma_variables() {
+        let ctx = context();
+        let state = play(&ctx, "mp1", &[], &["mp1.1", "mp1.2", "ax-mp"]);
+        let lemmas = [state.lemma(&ctx, &[], 0, "lem").unwrap()];
+        let state = play(&ctx, "mp2", &lemmas, &["mp2.1", "mp2.2", "lem"]);
+        assert_eq!(
+            state.export_proof(&ctx, &lemmas).unwrap(),
+            "wch wph mp2.1 mp2.2 ax-mp"
+        );
+    }
+
+    #[test]
+    fn lemmas_carry_disjoint_vars_of_their_proofs() {
+        let ctx = context();
+        let state = play(&ctx, "a1dv", &[], &["wch", "wph", "ax-dv"]);
+        let lemmas = [state.lemma(&ctx, &[], 0, "lem").unwrap()];
+        let names = ctx.metamath_db.name_result();
+        let pair = (names.get_atom(b"wch"), names.get_atom(b"wph"));
+        assert_eq!(lemmas[0].dvs, [pair]);
+        // a1 has no `$d ch ph`, so the lemma cannot prove it
+        assert!(!play(&ctx, "a1", &lemmas, &["lem"]).level_finished(&ctx));
+        assert!(play(&ctx, "a1dv", &lemmas, &["lem"]).level_finished(&ctx));
+    }
+}
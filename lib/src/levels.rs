@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use metamath_rs::{statement::StatementAddress, StatementRef, StatementType};
+
+use crate::Context;
+
+/// Heading of the section of set.mm with the personal mathboxes of contributors
+const MATHBOX_HEADING: &[u8] = b"Mathboxes for user contributions";
+
+/// Marker of statements kept only for compatibility
+const DISCOURAGED: &[u8] = b"(New usage is discouraged.)";
+
+/// Starts of the heading comments of set.mm, from parts down to subsubsections
+const HEADING_MARKERS: [&[u8]; 5] = [b"####", b"#*#*", b"=-=-", b"-.-.", b"-_-_"];
+
+/// Which provable statements level stepping skips
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LevelFilter {
+    /// Statements whose comment discourages new usage
+    pub skip_discouraged: bool,
+    /// Everything after the mathbox heading
+    pub skip_mathboxes: bool,
+    /// Statements whose proof has a `?` step
+    pub skip_incomplete: bool,
+}
+
+impl Default for LevelFilter {
+    fn default() -> Self {
+        Self {
+            skip_discouraged: true,
+            skip_mathboxes: true,
+            skip_incomplete: true,
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn is_mathbox_heading(stmt: &StatementRef) -> bool {
+    stmt.statement_type() == StatementType::Comment && {
+        let (buf, span) = stmt.comment_contents();
+        contains(span.as_ref(buf), MATHBOX_HEADING)
+    }
+}
+
+fn is_heading(stmt: &StatementRef) -> bool {
+    stmt.statement_type() == StatementType::Comment && {
+        let (buf, span) = stmt.comment_contents();
+        let text = span.as_ref(buf).trim_ascii_start();
+        HEADING_MARKERS
+            .iter()
+            .any(|marker| text.starts_with(marker))
+    }
+}
+
+/// Levels grouped by the headings between them
+#[derive(Default)]
+pub(crate) struct Chapters {
+    pub(crate) levels: Vec<Vec<StatementAddress>>,
+    /// Index of the chapter of each level
+    pub(crate) chapter_of: HashMap<StatementAddress, usize>,
+}
+
+impl Chapters {
+    fn new(ctx: &Context) -> Self {
+        let mut chapters = Self::default();
+        let mut levels = Vec::new();
+        for stmt in ctx.metamath_db.statements() {
+            if ctx.level_filter.skip_mathboxes && is_mathbox_heading(&stmt) {
+                break;
+            }
+            if is_heading(&stmt) && !levels.is_empty() {
+                chapters.levels.push(std::mem::take(&mut levels));
+            } else if ctx.is_level(&stmt) {
+                chapters
+                    .chapter_of
+                    .insert(stmt.address(), chapters.levels.len());
+                levels.push(stmt.address());
+            }
+        }
+        if !levels.is_empty() {
+            chapters.levels.push(levels);
+        }
+        chapters
+    }
+}
+
+fn is_discouraged(stmt: &StatementRef) -> bool {
+    stmt.associated_comment().is_some_and(|comment| {
+        let (buf, span) = comment.comment_contents();
+        contains(span.as_ref(buf), DISCOURAGED)
+    })
+}
+
+/// Whether the proof has an unknown step, which is a `?` token,
+/// or a `?` character among the letters of a compressed proof
+fn is_incomplete(stmt: &StatementRef) -> bool {
+    (0..stmt.proof_len()).any(|i| stmt.proof_slice_at(i).contains(&b'?'))
+}
+
+impl Context {
+    /// The same context, stepping through the levels `level_filter` keeps
+    pub fn with_level_filter(self, level_filter: LevelFilter) -> Self {
+        Self {
+            level_filter,
+            ..self
+        }
+    }
+
+    /// Built on first use, as it reads every comment
+    pub(crate) fn chapters(&self) -> &Chapters {
+        self.chapters.get_or_init(|| Chapters::new(self))
+    }
+
+    /// Whether a statement is a provable statement the level filter keeps,
+    /// whose goal and hypotheses the grammar parses
+    pub(crate) fn is_level(&self, stmt: &StatementRef) -> bool {
+        let filter = self.level_filter;
+        stmt.statement_type() == StatementType::Provable
+            && self.rule(stmt.address()).is_some()
+            && !(filter.skip_discouraged && is_discouraged(stmt))
+            && !(filter.skip_incomplete && is_incomplete(stmt))
+    }
+
+    /// Levels among `stmts`, stopping at the mathboxes if the filter skips them
+    pub(crate) fn levels_in<'a>(
+        &'a self,
+        stmts: impl Iterator<Item = StatementRef<'a>> + 'a,
+    ) -> impl Iterator<Item = StatementRef<'a>> + 'a {
+        stmts
+            .take_while(|stmt| !(self.level_filter.skip_mathboxes && is_mathbox_heading(stmt)))
+            .filter(|stmt| self.is_level(stmt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LevelFilter;
+    use crate::{Context, TypesetMode};
+
+    /// Two chapters with a discouraged, an incomplete and a mathbox statement
+    const DB: &str = "
+        $( $j syntax 'wff'; syntax '|-' as 'wff'; $)
+        $c ( ) -> wff |- $.
+        $v ph ps $.
+        wph $f wff ph $.
+        wps $f wff ps $.
+        wi $a wff ( ph -> ps ) $.
+        ax-1 $a |- ( ph -> ( ps -> ph ) ) $.
+        $( #### Chapter one $)
+        $( Simplification. $)
+        th1 $p |- ( ph -> ( ps -> ph ) ) $= wph wps ax-1 $.
+        $( Duplicate of ~ th1 .  (New usage is discouraged.) $)
+        old $p |- ( ph -> ( ps -> ph ) ) $= wph wps ax-1 $.
+        $( =-=-=- Chapter two $)
+        todo $p |- ( ps -> ( ph -> ps ) ) $= ? $.
+        $( Mathboxes for user contributions $)
+        mine $p |- ( ps -> ( ps -> ps ) ) $= wps wps ax-1 $.
+    ";
+
+    fn context(level_filter: LevelFilter) -> Context {
+        Context::load("test.mm", DB, TypesetMode::AltHtml).with_level_filter(level_filter)
+    }
+
+    fn levels(ctx: &Context) -> Vec<String> {
+        ctx.levels_in(ctx.metamath_db.statements())
+            .map(|stmt| ctx.label(stmt.address()))
+            .collect()
+    }
+
+    fn chapters(ctx: &Context) -> Vec<Vec<String>> {
+        let chapters = &ctx.chapters().levels;
+        chapters
+            .iter()
+            .map(|levels| levels.iter().map(|&addr| ctx.label(addr)).collect())
+            .collect()
+    }
+
+    const KEEP_ALL: LevelFilter = LevelFilter {
+        skip_discouraged: false,
+        skip_mathboxes: false,
+        skip_incomplete: false,
+    };
+
+    #[test]
+    fn default_filter_skips_statements() {
+        let ctx = context(LevelFilter::default());
+        assert_eq!(levels(&ctx), ["th1"]);
+        assert_eq!(chapters(&ctx), [["th1"]]);
+    }
+
+    #[test]
+    fn filter_flags_keep_statements() {
+        let ctx = context(KEEP_ALL);
+        assert_eq!(levels(&ctx), ["th1", "old", "todo", "mine"]);
+        assert_eq!(chapters(&ctx), [["th1", "old"], ["todo", "mine"]]);
+        let only = |filter| levels(&context(filter));
+        let discouraged = only(LevelFilter {
+            skip_discouraged: true,
+            ..KEEP_ALL
+        });
+        assert_eq!(discouraged, ["th1", "todo", "mine"]);
+        let incomplete = only(LevelFilter {
+            skip_incomplete: true,
+            ..KEEP_ALL
+        });
+        assert_eq!(incomplete, ["th1", "old", "mine"]);
+        let mathboxes = only(LevelFilter {
+            skip_mathboxes: true,
+            ..KEEP_ALL
+        });
+        assert_eq!(mathboxes, ["th1", "old", "todo"]);
+    }
+}
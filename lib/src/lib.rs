@@ -1,33 +1,100 @@
-use std::{collections::HashSet, iter};
+mod cache;
+mod campaign;
+mod comment;
+mod difficulty;
+mod expr;
+mod graph;
+mod index;
+mod lemma;
+mod levels;
+mod library;
+mod macros;
+mod manifest;
+mod mathml;
+mod progress;
+mod prop;
+mod prover;
+mod tactics;
+mod trim;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+};
 
 use itertools::Itertools;
 use metamath_rs::{
     database::DbOptions,
-    formula::Substitutions,
-    nameck::{Atom, NameReader},
+    formula::{Label, TypeCode},
+    nameck::NameReader,
     scopeck::Hyp,
     statement::TokenPtr,
     Database, Formula, StatementRef, StatementType,
 };
 
+pub use cache::{ButtonCache, Memo, StepCache};
+pub use comment::CommentPart;
+pub use difficulty::{Difficulty, LevelOrder};
+use expr::Token;
+pub use expr::{Expr, Tree, WorkSubsts};
+pub use graph::{DepGraph, GraphEdge, GraphNode, NodeStatus};
+use index::StatementIndex;
+pub use lemma::{Lemma, SavedLemma, SavedProof};
+use levels::Chapters;
+pub use levels::LevelFilter;
+use library::LibraryIndex;
+pub use library::{Library, Matched, SearchResult};
+pub use macros::{Macro, MacroAction};
+use manifest::LevelPack;
+pub use manifest::{Manifest, ManifestError, ManifestLevel};
 pub use metamath_rs::statement::StatementAddress;
+pub use progress::Progress;
+pub use prop::TruthTable;
+pub use tactics::Tactic;
+pub use trim::LevelSpecError;
 
 pub enum TypesetMode {
     Latex,
     AltHtml,
+    /// Nested MathML following the grammar, with the text of the althtml definitions
+    MathML,
 }
 
 pub struct Context {
     metamath_db: Database,
     typeset_mode: TypesetMode,
+    /// Which provable statements are skipped when stepping through levels
+    level_filter: LevelFilter,
+    /// Levels to follow instead of the database order
+    level_pack: Option<LevelPack>,
+    statement_index: StatementIndex,
+    /// Built on first use, as it parses every assertion
+    library_index: OnceLock<LibraryIndex>,
+    chapters: OnceLock<Chapters>,
+    /// Difficulty of every level of the chapters, built on first use
+    difficulties: OnceLock<HashMap<StatementAddress, Difficulty>>,
 }
 
 fn from_utf8(bytes: &[u8]) -> String {
     std::str::from_utf8(bytes).unwrap().into()
 }
 
-fn formula_eq(a: &Formula, b: &Formula) -> bool {
-    a == b && a.get_typecode() == b.get_typecode()
+/// Why typed math tokens are not an expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset of the offending token, or the length of the text if it ended early
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at character {}", self.message, self.position)
+    }
 }
 
 impl Context {
@@ -47,34 +114,140 @@ impl Context {
         metamath_db.scope_pass();
         metamath_db.typesetting_pass();
         metamath_db.grammar_pass();
-        Self {
+        let mut ctx = Self {
             metamath_db,
             typeset_mode,
-        }
+            level_filter: LevelFilter::default(),
+            level_pack: None,
+            statement_index: StatementIndex::default(),
+            library_index: OnceLock::new(),
+            chapters: OnceLock::new(),
+            difficulties: OnceLock::new(),
+        };
+        // Parsing every statement up front keeps pushes cheap
+        ctx.statement_index = StatementIndex::new(&ctx);
+        ctx
     }
 
-    pub fn initial_state(&self, level: Option<&str>) -> State {
+    fn level_addr(&self, level: Option<&str>) -> StatementAddress {
         let db = &self.metamath_db;
-        let current_level_stmt_addr = match level {
+        match level {
             Some(level) => db.statement(level.as_bytes()).unwrap().address(),
-            None => db
-                .statements()
-                .find(|stmt| stmt.statement_type() == StatementType::Provable)
-                .unwrap_or_else(|| {
-                    panic!("only {} statements", self.metamath_db.statements().count())
-                })
-                .address(),
-        };
-        State {
+            None => match &self.level_pack {
+                Some(pack) => pack.first().expect("a manifest without levels"),
+                None => self
+                    .levels_in(db.statements())
+                    .next()
+                    .unwrap_or_else(|| {
+                        panic!("only {} statements", self.metamath_db.statements().count())
+                    })
+                    .address(),
+            },
+        }
+    }
+
+    /// The level after `addr`, in the order of the manifest if there is one
+    fn next_level_addr(
+        &self,
+        addr: StatementAddress,
+        order: LevelOrder,
+        solved: &HashSet<StatementAddress>,
+    ) -> Option<StatementAddress> {
+        match &self.level_pack {
+            Some(pack) => pack.next(addr),
+            None if order == LevelOrder::Gentle => self.next_gentle_level(addr, solved),
+            None => Some(
+                self.levels_in(self.metamath_db.statements_range_address(addr..))
+                    .find(|stmt| stmt.address() != addr)?
+                    .address(),
+            ),
+        }
+    }
+
+    pub fn initial_state(&self, level: Option<&str>) -> State {
+        self.level_state(self.level_addr(level))
+    }
+
+    /// The start of a level, with the start stack of the manifest pushed
+    fn level_state(&self, current_level_stmt_addr: StatementAddress) -> State {
+        self.start_state(current_level_stmt_addr)
+            .expect("start stacks are checked by set_manifest")
+    }
+
+    /// `level_state`, or the index of the first entry of the start stack that cannot be pushed
+    pub(crate) fn start_state(
+        &self,
+        current_level_stmt_addr: StatementAddress,
+    ) -> Result<State, usize> {
+        let state = State {
             current_level_stmt_addr,
+            sandbox_goal: None,
+            library: Library::Reference,
+            level_order: LevelOrder::Database,
+            unlocked: Arc::default(),
+            proof_stack: Vec::new(),
+            next_work_var: 0,
+            id: next_state_id(),
+        };
+        let Some(pack) = &self.level_pack else {
+            return Ok(state);
+        };
+        pack.start_stack(current_level_stmt_addr)
+            .iter()
+            .enumerate()
+            .try_fold(state, |state, (i, &addr)| {
+                state.push(self, &[], Step::Stmt(addr)).ok_or(i)
+            })
+    }
+
+    /// Address of the statement with the given label
+    pub fn statement_addr(&self, label: &str) -> Option<StatementAddress> {
+        Some(self.metamath_db.statement(label.as_bytes())?.address())
+    }
+
+    /// State proving a typed goal, e.g. `|- ( ph -> ph )`,
+    /// in open-library mode with every assertion before `before` available
+    pub fn sandbox_state(&self, goal: &str, before: StatementAddress) -> Result<State, ParseError> {
+        let goal = self.parse_expr(goal)?;
+        if goal.typecode != self.metamath_db.grammar_result().provable_typecode() {
+            return Err(ParseError {
+                position: 0,
+                message: "not a provable statement",
+            });
+        }
+        Ok(State {
+            current_level_stmt_addr: before,
+            sandbox_goal: Some(goal),
+            library: Library::Open,
+            level_order: LevelOrder::Database,
+            unlocked: Arc::default(),
             proof_stack: Vec::new(),
+            next_work_var: 0,
+            id: next_state_id(),
+        })
+    }
+
+    pub fn initial_goal_tree(&self, level: Option<&str>) -> GoalTree {
+        let current_level_stmt_addr = self.level_addr(level);
+        GoalTree {
+            current_level_stmt_addr,
+            root: Goal::new(self.level_goal(current_level_stmt_addr)),
+            selected: 0,
+            level_order: LevelOrder::Database,
+            unlocked: Arc::default(),
+            dvs: Arc::default(),
+            next_work_var: 0,
+            id: next_state_id(),
         }
     }
 
     fn deps(&self, addr: StatementAddress) -> Vec<StatementAddress> {
         let db = &self.metamath_db;
         let stmt = db.statement_by_address(addr);
-        let proof_tree = db.get_proof_tree(stmt).unwrap();
+        // Incomplete proofs have no tree
+        let Some(proof_tree) = db.get_proof_tree(stmt) else {
+            return Vec::new();
+        };
         proof_tree
             .with_steps(db, |_cur, stmt, _hyps| stmt.address())
             .into_iter()
@@ -87,6 +260,7 @@ impl Context {
         let typeset_defs = match self.typeset_mode {
             TypesetMode::Latex => &typesetting_data.latex_defs,
             TypesetMode::AltHtml => &typesetting_data.alt_html_defs,
+            TypesetMode::MathML => return self.mathml_token(token),
         };
         match typeset_defs.get(token) {
             Some((_, _, token)) => from_utf8(token),
@@ -94,20 +268,84 @@ impl Context {
         }
     }
 
+    /// Letter for work variables of a typecode, as in `&W1` for `wff`
+    fn work_var_kind(&self, typecode: TypeCode) -> char {
+        let names = self.metamath_db.name_result();
+        names
+            .atom_name(typecode)
+            .first()
+            .map_or('W', |c| c.to_ascii_uppercase() as char)
+    }
+
+    fn render_work_var(&self, typecode: TypeCode, n: u32) -> String {
+        let kind = self.work_var_kind(typecode);
+        match self.typeset_mode {
+            TypesetMode::Latex => format!("\\&\\mathrm{{{kind}}}_{{{n}}}"),
+            TypesetMode::AltHtml => format!("&amp;{kind}<sub>{n}</sub>"),
+            TypesetMode::MathML => format!("<msub><mi>&amp;{kind}</mi><mn>{n}</mn></msub>"),
+        }
+    }
+
     fn render_tokens<'a>(&self, toks: impl Iterator<Item = TokenPtr<'a>>) -> String {
-        toks.map(|tok| self.render_token(tok)).join(" ")
+        let rendered = toks.map(|tok| self.render_token(tok)).join(" ");
+        match self.typeset_mode {
+            // Without a parse tree, e.g. for math in comments
+            TypesetMode::MathML => mathml::math_element(&rendered),
+            _ => rendered,
+        }
     }
 
-    fn render_formula(&self, formula: &Formula) -> String {
-        let db = &self.metamath_db;
-        let names = db.name_result();
-        let toks = iter::once(formula.get_typecode())
-            .chain(formula.as_ref(db))
-            .map(|tok| names.atom_name(tok));
-        self.render_tokens(toks)
+    fn render_math(&self, toks: Vec<Token>) -> String {
+        toks.into_iter()
+            .map(|tok| match tok {
+                Token::Symbol(tok) => self.render_token(tok),
+                Token::Work(typecode, n) => self.render_work_var(typecode, n),
+            })
+            .join(" ")
+    }
+
+    fn render_expr(&self, expr: &Expr) -> String {
+        if let TypesetMode::MathML = self.typeset_mode {
+            return self.expr_mathml(expr);
+        }
+        self.render_math(expr.tokens(&self.metamath_db))
+    }
+
+    fn render_tree(&self, tree: &Tree) -> String {
+        if let TypesetMode::MathML = self.typeset_mode {
+            return self.tree_mathml(tree);
+        }
+        let mut toks = Vec::new();
+        tree.tokens(&self.metamath_db, &mut toks);
+        self.render_math(toks)
+    }
+
+    fn tokens_to_ascii(&self, toks: Vec<Token>) -> String {
+        toks.into_iter()
+            .map(|tok| match tok {
+                Token::Symbol(tok) => from_utf8(tok),
+                Token::Work(typecode, n) => format!("&{}{n}", self.work_var_kind(typecode)),
+            })
+            .join(" ")
+    }
+
+    /// Math tokens separated by spaces, as written in the database
+    fn expr_to_ascii(&self, expr: &Expr) -> String {
+        self.tokens_to_ascii(expr.tokens(&self.metamath_db))
+    }
+
+    fn tree_to_ascii(&self, tree: &Tree) -> String {
+        let mut toks = Vec::new();
+        tree.tokens(&self.metamath_db, &mut toks);
+        self.tokens_to_ascii(toks)
     }
 
     fn render_stmt(&self, stmt: StatementAddress) -> String {
+        if let TypesetMode::MathML = self.typeset_mode {
+            if let Some(expr) = self.stmt_expr(stmt) {
+                return self.expr_mathml(expr);
+            }
+        }
         let toks = self
             .metamath_db
             .statement_by_address(stmt)
@@ -132,6 +370,16 @@ impl Context {
         }
     }
 
+    fn essential_hyps(&self, stmt_addr: StatementAddress) -> Vec<StatementAddress> {
+        let db = &self.metamath_db;
+        self.hyp_addrs(stmt_addr)
+            .into_iter()
+            .filter(|&addr| {
+                db.statement_by_address(addr).statement_type() == StatementType::Essential
+            })
+            .collect()
+    }
+
     pub fn render_inference(&self, stmt_addr: StatementAddress) -> (Vec<String>, String) {
         let conclusion = self.render_stmt(stmt_addr);
         let hyps = self
@@ -142,197 +390,1075 @@ impl Context {
         (hyps, conclusion)
     }
 
+    pub fn render_step_inference(&self, step: Step, lemmas: &[Lemma]) -> (Vec<String>, String) {
+        match step {
+            Step::Stmt(stmt_addr) => self.render_inference(stmt_addr),
+            Step::Lemma(i) => {
+                let rule = &lemmas[i].rule;
+                let hyps = rule
+                    .hyps
+                    .iter()
+                    .map(|(_, hyp)| self.render_expr(hyp))
+                    .collect();
+                (hyps, self.render_expr(&rule.conclusion))
+            }
+        }
+    }
+
     pub fn label(&self, stmt: StatementAddress) -> String {
         let l = self.metamath_db.statement_by_address(stmt).label();
         from_utf8(l)
     }
 
-    fn stmt_to_formula(&self, stmt: StatementRef) -> Formula {
+    pub fn step_label(&self, step: Step, lemmas: &[Lemma]) -> String {
+        match step {
+            Step::Stmt(stmt_addr) => self.label(stmt_addr),
+            Step::Lemma(i) => lemmas[i].name.clone(),
+        }
+    }
+
+    /// Database statement or lemma with the given label
+    pub fn step_by_label(&self, label: &str, lemmas: &[Lemma]) -> Option<Step> {
+        match self.metamath_db.statement(label.as_bytes()) {
+            Some(stmt) => Some(Step::Stmt(stmt.address())),
+            None => Some(Step::Lemma(
+                lemmas.iter().position(|lemma| lemma.name == label)?,
+            )),
+        }
+    }
+
+    /// Typecode of formulas, which provable statements assert
+    fn logic_typecode(&self) -> TypeCode {
+        self.metamath_db.grammar_result().logic_typecode()
+    }
+
+    /// Goal of a level, which the proof stack must end with
+    pub fn level_goal(&self, level_addr: StatementAddress) -> Expr {
+        self.stmt_expr(level_addr)
+            .expect("levels are parsed")
+            .clone()
+    }
+
+    /// Parsed math string of an assertion or hypothesis, `None` if the grammar rejects it
+    fn stmt_expr(&self, addr: StatementAddress) -> Option<&Expr> {
+        self.statement_index.expr(addr)
+    }
+
+    fn stmt_to_formula(&self, stmt: StatementRef) -> Option<Formula> {
         let db = &self.metamath_db;
         let grammar = db.grammar_result();
         let names = db.name_result();
         grammar
             .parse_statement(&stmt, names, &mut NameReader::new(names))
-            .unwrap()
+            .ok()
+    }
+
+    fn stmt_to_expr(&self, stmt: StatementRef) -> Option<Expr> {
+        Some(Expr::from_formula(
+            &self.metamath_db,
+            &self.stmt_to_formula(stmt)?,
+        ))
     }
 
-    fn unify_hyps(&self, hyps: &[&Hyp], stack_top: &[Formula]) -> Option<Substitutions> {
+    /// Parse math tokens separated by spaces, e.g. `|- ( ph -> ph )`
+    fn parse_expr(&self, text: &str) -> Result<Expr, ParseError> {
         let db = &self.metamath_db;
+        let grammar = db.grammar_result();
+        let names = db.name_result();
+        let tokens = text
+            .split_whitespace()
+            .map(|tok| (tok.as_ptr() as usize - text.as_ptr() as usize, tok))
+            .collect::<Vec<_>>();
+        let error = |i: usize, message| ParseError {
+            position: tokens.get(i).map_or(text.len(), |(position, _)| *position),
+            message,
+        };
+        let symbols = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, (_, tok))| match names.lookup_symbol(tok.as_bytes()) {
+                Some(symbol) => Ok(symbol.atom),
+                None => Err(error(i, "unknown symbol")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (&typecode, symbols) = symbols
+            .split_first()
+            .ok_or_else(|| error(0, "missing typecode"))?;
+        let mut symbols = symbols.iter().copied();
+        // Tokens read by the parser, including the typecode
+        let mut num_read = 1;
+        let formula = {
+            let mut symbols = symbols.by_ref().inspect(|_| num_read += 1);
+            if typecode == grammar.provable_typecode() {
+                grammar.parse_formula(&mut symbols, &[grammar.logic_typecode()], true, names)
+            } else {
+                grammar.parse_formula(&mut symbols, &[typecode], false, names)
+            }
+        };
+        // The parser fails at the last token it read
+        let formula = formula.map_err(|_| error(num_read - 1, "syntax error"))?;
+        if symbols.next().is_some() {
+            return Err(error(num_read, "unexpected token"));
+        }
+        Ok(Expr::from_formula(db, &formula))
+    }
 
-        // Ensure no essential hypotheses are ignored
-        if hyps
+    /// Hypotheses and conclusion of an assertion, `None` for hypotheses
+    fn rule(&self, stmt_addr: StatementAddress) -> Option<&Rule> {
+        self.statement_index.rule(stmt_addr)
+    }
+
+    fn step_rule<'a>(&'a self, step: Step, lemmas: &'a [Lemma]) -> Option<&'a Rule> {
+        match step {
+            Step::Stmt(stmt_addr) => self.rule(stmt_addr),
+            Step::Lemma(i) => Some(&lemmas.get(i)?.rule),
+        }
+    }
+
+    /// Assign stack entries to hypotheses, returning the hypothesis of each entry.
+    /// Essential hypotheses must all be matched, in order,
+    /// while floating ones may match any entry or none.
+    fn match_hyps(
+        &self,
+        hyps: &[(bool, Expr)],
+        entries: &[Entry],
+    ) -> Option<(WorkSubsts, Vec<usize>)> {
+        let essential = hyps
             .iter()
-            .rev()
-            .skip(stack_top.len())
-            .any(|hyp| matches!(hyp, Hyp::Essential(_, _)))
-        {
+            .positions(|(essential, _)| *essential)
+            .collect::<Vec<_>>();
+        let mut assignment = Vec::with_capacity(entries.len());
+        let mut used = vec![false; hyps.len()];
+        let substs = self.match_hyps_from(
+            hyps,
+            &essential,
+            entries,
+            &mut assignment,
+            &mut used,
+            &WorkSubsts::new(),
+        )?;
+        Some((substs, assignment))
+    }
+
+    /// Whether a stack entry unifies with a hypothesis, extending `substs`
+    pub fn unify_hyp(&self, entry: &Expr, hyp: &Expr, substs: &mut WorkSubsts) -> bool {
+        entry.typecode == hyp.typecode && entry.tree.unify(&hyp.tree, &self.metamath_db, substs)
+    }
+
+    /// Backtracking search of `match_hyps`, where `essential` are the essential hypotheses
+    /// left to match and `used` marks the floating ones already matched
+    fn match_hyps_from(
+        &self,
+        hyps: &[(bool, Expr)],
+        essential: &[usize],
+        entries: &[Entry],
+        assignment: &mut Vec<usize>,
+        used: &mut [bool],
+        substs: &WorkSubsts,
+    ) -> Option<WorkSubsts> {
+        let Some((entry, rest)) = entries.split_first() else {
+            return essential.is_empty().then(|| substs.clone());
+        };
+        if !typecodes_fit(hyps, essential, entries, used) {
             return None;
         }
+        // The next essential hypothesis, or any floating one not matched yet
+        let candidates = essential
+            .split_first()
+            .map(|(&i, essential)| (i, essential))
+            .into_iter()
+            .chain(
+                (0..hyps.len())
+                    .filter(|&i| !hyps[i].0 && !used[i])
+                    .map(|i| (i, essential)),
+            )
+            .collect::<Vec<_>>();
+        for (i, essential) in candidates {
+            let mut substs = substs.clone();
+            if !self.unify_hyp(&entry.expr, &hyps[i].1, &mut substs) {
+                continue;
+            }
+            used[i] = true;
+            assignment.push(i);
+            if let Some(substs) =
+                self.match_hyps_from(hyps, essential, rest, assignment, used, &substs)
+            {
+                return Some(substs);
+            }
+            used[i] = false;
+            assignment.pop();
+        }
+        None
+    }
+}
 
-        let mut substs = Substitutions::new();
-        for (stack_hyp, hyp) in stack_top.iter().rev().zip(hyps.iter().rev()) {
-            let hyp = db.statement_by_address(hyp.address());
-            let hyp = self.stmt_to_formula(hyp);
-            if stack_hyp.get_typecode() != hyp.get_typecode() {
-                return None;
+/// Whether the typecodes of `entries` allow matching them with the hypotheses left:
+/// each essential one needs an entry, and each entry an essential or unused floating one
+fn typecodes_fit(
+    hyps: &[(bool, Expr)],
+    essential: &[usize],
+    entries: &[Entry],
+    used: &[bool],
+) -> bool {
+    // Entries, essential hypotheses and unused floating hypotheses of each typecode
+    let mut counts = HashMap::<TypeCode, (usize, usize, usize)>::new();
+    for entry in entries {
+        counts.entry(entry.expr.typecode).or_default().0 += 1;
+    }
+    for &i in essential {
+        counts.entry(hyps[i].1.typecode).or_default().1 += 1;
+    }
+    for (i, (is_essential, hyp)) in hyps.iter().enumerate() {
+        if !is_essential && !used[i] {
+            counts.entry(hyp.typecode).or_default().2 += 1;
+        }
+    }
+    counts.values().all(|&(entries, essential, floating)| {
+        essential <= entries && entries <= essential + floating
+    })
+}
+
+/// Hypotheses, each marked whether it is essential, and conclusion of an assertion
+#[derive(Clone, Debug)]
+struct Rule {
+    hyps: Vec<(bool, Expr)>,
+    conclusion: Expr,
+}
+
+impl Rule {
+    /// Replace every variable with a fresh work variable
+    fn instantiate(&self, db: &Database, next_work_var: &mut u32) -> Self {
+        self.instantiate_vars(db, next_work_var).0
+    }
+
+    /// Like `instantiate`, also returning the work variable replacing each variable
+    fn instantiate_vars(
+        &self,
+        db: &Database,
+        next_work_var: &mut u32,
+    ) -> (Self, Vec<(Label, Tree)>) {
+        let mut vars = Vec::new();
+        self.conclusion
+            .tree
+            .for_each_var(&mut |label| vars.push(label));
+        for (_, hyp) in &self.hyps {
+            hyp.tree.for_each_var(&mut |label| vars.push(label));
+        }
+        let work_vars = vars
+            .into_iter()
+            .unique()
+            .map(|label| {
+                let work_var = Tree::Work(Tree::Var(label).typecode(db), *next_work_var);
+                *next_work_var += 1;
+                (label, work_var)
+            })
+            .collect::<Vec<_>>();
+        let work_var_map = work_vars.iter().cloned().collect::<HashMap<_, _>>();
+        let instantiate = |expr: &Expr| Expr {
+            typecode: expr.typecode,
+            tree: expr
+                .tree
+                .map_vars(&mut |label| work_var_map[&label].clone()),
+        };
+        let rule = Self {
+            hyps: self
+                .hyps
+                .iter()
+                .map(|(essential, hyp)| (*essential, instantiate(hyp)))
+                .collect(),
+            conclusion: instantiate(&self.conclusion),
+        };
+        (rule, work_vars)
+    }
+}
+
+/// Assigns stack entries to the hypotheses of a rule, as `Context::match_hyps` does
+pub type MatchHyps = fn(&Context, &[(bool, Expr)], &[Entry]) -> Option<(WorkSubsts, Vec<usize>)>;
+
+/// A rule instantiated for a push, with the stack entries its hypotheses take
+struct TopMatch {
+    rule: Rule,
+    substs: WorkSubsts,
+    /// Hypothesis of each of the top `num_pop` entries
+    assignment: Vec<usize>,
+    num_pop: usize,
+    /// `$d` value pairs of the pushed entry
+    dvs: Vec<(Tree, Tree)>,
+}
+
+/// A statement usable as a step of a proof
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Step {
+    Stmt(StatementAddress),
+    /// Index into the player's lemmas
+    Lemma(usize),
+}
+
+/// How a stack entry was derived
+#[derive(Debug)]
+pub struct Proof {
+    pub step: Step,
+
+    /// Proofs of the stack entries consumed by the essential hypotheses of `step`, in order
+    pub hyps: Vec<Arc<Proof>>,
+}
+
+impl Proof {
+    /// Hypotheses of the level used by the proof
+    fn level_hyps(&self, db: &Database, out: &mut Vec<StatementAddress>) {
+        match self.step {
+            Step::Stmt(addr)
+                if db.statement_by_address(addr).statement_type() == StatementType::Essential =>
+            {
+                out.push(addr)
+            }
+            _ => {
+                for hyp in &self.hyps {
+                    hyp.level_hyps(db, out);
+                }
             }
-            stack_hyp.unify(&hyp, &mut substs).ok()?
         }
+    }
+}
+
+/// An expression on the proof stack, shared between the states that contain it
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub expr: Arc<Expr>,
+    pub proof: Arc<Proof>,
+    /// Values the `$d`s of the steps of `proof` keep apart,
+    /// rechecked as work variables get assigned
+    dvs: Arc<Vec<(Tree, Tree)>>,
+}
 
-        Some(substs)
+impl Entry {
+    fn has_work_vars(&self) -> bool {
+        self.expr.has_work_vars()
+            || self
+                .dvs
+                .iter()
+                .any(|(a, b)| a.has_work_vars() || b.has_work_vars())
+    }
+
+    fn substitute(&self, substs: &WorkSubsts) -> Self {
+        Self {
+            expr: Arc::new(self.expr.substitute(substs)),
+            proof: self.proof.clone(),
+            dvs: Arc::new(index::substitute_dvs(&self.dvs, substs)),
+        }
     }
 }
 
+/// Something the player does to the proof stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Push(Step),
+    Swap(usize, usize),
+    Delete(usize),
+    Copy(usize),
+    Move(usize, usize),
+}
+
 /// The game state
 #[derive(Clone, Debug)]
 pub struct State {
     /// Index of the statement representing the current level
     pub current_level_stmt_addr: StatementAddress,
 
-    pub proof_stack: Vec<Formula>,
+    /// Goal typed by the player in sandbox mode, proven with the assertions before
+    /// `current_level_stmt_addr` instead of that statement
+    pub sandbox_goal: Option<Expr>,
+
+    /// Which statements may be pushed
+    pub library: Library,
+
+    /// Which level comes next
+    pub level_order: LevelOrder,
+
+    /// Theorems the player has proven as levels, usable in unlock mode
+    unlocked: Arc<HashSet<StatementAddress>>,
+
+    pub proof_stack: Vec<Entry>,
+
+    /// Number of the next fresh work variable
+    next_work_var: u32,
+
+    /// Identifies the state, which clones share and every transition renews
+    id: u64,
+}
+
+fn next_state_id() -> u64 {
+    static NEXT_STATE_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_STATE_ID.fetch_add(1, Ordering::Relaxed)
 }
 
 impl State {
-    fn push(&self, ctx: &Context, step_addr: StatementAddress) -> Option<Self> {
-        let db = &ctx.metamath_db;
-        let names = db.name_result();
-        let scopes = db.scope_result();
-        let step_stmt = db.statement_by_address(step_addr);
-        let step_frame = scopes.get(step_stmt.label());
-        let hyps = match step_frame {
-            Some(frame) => &frame.hypotheses,
-            None => &Default::default(),
-        };
-        let proof_stack = if hyps.is_empty() {
-            let formula = ctx.stmt_to_formula(step_stmt);
-            let mut stack = self.proof_stack.clone();
-            stack.push(formula);
-            stack
-        } else {
-            let conclusion = ctx.stmt_to_formula(step_stmt);
-            let mut stack = self.proof_stack.clone();
-            let max_num_pop = stack.len().min(hyps.len());
-            let (substs, num_pop) = hyps
-                .iter()
-                .permutations(hyps.len())
-                .cartesian_product(1..=max_num_pop)
-                .find_map(|(hyps, num_pop)| {
-                    let sp = stack.len().checked_sub(num_pop)?;
-                    let stack_hyps = &stack[sp..];
-                    let substs = ctx.unify_hyps(&hyps, stack_hyps)?;
-                    let subst_vars = substs
-                        .iter()
-                        .map(|(var, _)| {
-                            names.get_atom(&db.statement_by_label(*var).unwrap().math_at(1))
-                        })
-                        .collect::<HashSet<Atom>>();
-                    let step_vars = step_frame?
-                        .var_list
-                        .iter()
-                        .copied()
-                        .collect::<HashSet<Atom>>();
-                    if subst_vars == step_vars {
-                        Some((substs, num_pop))
-                    } else {
-                        None
-                    }
-                })?;
-            for _ in 0..num_pop {
-                stack.pop()?;
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn push(&self, ctx: &Context, lemmas: &[Lemma], step: Step) -> Option<Self> {
+        self.push_with(ctx, lemmas, step, Context::match_hyps)
+    }
+
+    /// Whether pushing `step` succeeds, without building the next state
+    pub fn can_push(&self, ctx: &Context, lemmas: &[Lemma], step: Step) -> bool {
+        self.can_push_with(ctx, lemmas, step, Context::match_hyps)
+    }
+
+    /// The level whose `$d`s apply, none in sandbox mode
+    fn dv_level(&self) -> Option<StatementAddress> {
+        self.sandbox_goal
+            .is_none()
+            .then_some(self.current_level_stmt_addr)
+    }
+
+    fn can_push_with(
+        &self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        step: Step,
+        match_hyps: MatchHyps,
+    ) -> bool {
+        match ctx.step_rule(step, lemmas) {
+            Some(rule) => {
+                let mut next_work_var = self.next_work_var;
+                self.match_top(ctx, lemmas, step, rule, &mut next_work_var, match_hyps)
+                    .is_some()
             }
-            let subst_conclusion = conclusion.substitute(&substs);
-            stack.push(subst_conclusion);
-            stack
+            // Hypotheses are pushed as-is
+            None => matches!(step, Step::Stmt(addr) if ctx.stmt_expr(addr).is_some()),
+        }
+    }
+
+    /// Instantiate the rule of `step` and match it against the top of the stack,
+    /// preferring to consume as much of the stack as possible
+    fn match_top(
+        &self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        step: Step,
+        rule: &Rule,
+        next_work_var: &mut u32,
+        match_hyps: MatchHyps,
+    ) -> Option<TopMatch> {
+        let (rule, work_vars) = rule.instantiate_vars(&ctx.metamath_db, next_work_var);
+        let stack = &self.proof_stack;
+        let max_num_pop = stack.len().min(rule.hyps.len());
+        let num_essential = rule.hyps.iter().filter(|(essential, _)| *essential).count();
+        let level = self.dv_level();
+        let (substs, assignment, num_pop, dvs) =
+            (num_essential..=max_num_pop).rev().find_map(|num_pop| {
+                let sp = stack.len() - num_pop;
+                let (substs, assignment) = match_hyps(ctx, &rule.hyps, &stack[sp..])?;
+                let step_dvs = ctx.dv_values(step, lemmas, &work_vars);
+                let mut dvs = index::substitute_dvs(&step_dvs, &substs);
+                for entry in &stack[sp..] {
+                    dvs.extend(index::substitute_dvs(&entry.dvs, &substs));
+                }
+                // Assignments may also break the `$d`s of the entries left on the stack
+                let kept_hold = substs.is_empty()
+                    || stack[..sp].iter().all(|entry| {
+                        ctx.dvs_hold(level, &index::substitute_dvs(&entry.dvs, &substs))
+                    });
+                (kept_hold && ctx.dvs_hold(level, &dvs))
+                    .then_some((substs, assignment, num_pop, dvs))
+            })?;
+        Some(TopMatch {
+            rule,
+            substs,
+            assignment,
+            num_pop,
+            dvs,
+        })
+    }
+
+    /// `push`, assigning stack entries to hypotheses with `match_hyps`
+    fn push_with(
+        &self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        step: Step,
+        match_hyps: MatchHyps,
+    ) -> Option<Self> {
+        let Some(rule) = ctx.step_rule(step, lemmas) else {
+            // Hypotheses are pushed as-is, their variables are those of the level
+            let Step::Stmt(step_addr) = step else {
+                return None;
+            };
+            let mut new = self.clone();
+            new.id = next_state_id();
+            new.proof_stack.push(Entry {
+                expr: Arc::new(ctx.stmt_expr(step_addr)?.clone()),
+                proof: Arc::new(Proof {
+                    step,
+                    hyps: Vec::new(),
+                }),
+                dvs: Arc::default(),
+            });
+            return Some(new);
         };
+        let mut next_work_var = self.next_work_var;
+        let TopMatch {
+            rule,
+            substs,
+            assignment,
+            num_pop,
+            dvs,
+        } = self.match_top(ctx, lemmas, step, rule, &mut next_work_var, match_hyps)?;
+        let stack = &self.proof_stack;
+        let sp = stack.len() - num_pop;
+        // Entries consumed by floating hypotheses are only syntax, rebuilt on export
+        let mut consumed = assignment
+            .iter()
+            .zip(&stack[sp..])
+            .filter(|&(&i, _)| rule.hyps[i].0)
+            .collect::<Vec<_>>();
+        consumed.sort_by_key(|(i, _)| **i);
+        let proof = Arc::new(Proof {
+            step,
+            hyps: consumed
+                .into_iter()
+                .map(|(_, entry)| entry.proof.clone())
+                .collect(),
+        });
+        // Refine work variables across the whole stack, sharing the entries without any
+        let mut proof_stack = stack[..sp]
+            .iter()
+            .map(|entry| {
+                if substs.is_empty() || !entry.has_work_vars() {
+                    entry.clone()
+                } else {
+                    entry.substitute(&substs)
+                }
+            })
+            .collect::<Vec<_>>();
+        proof_stack.push(Entry {
+            expr: Arc::new(rule.conclusion.substitute(&substs)),
+            proof,
+            dvs: Arc::new(dvs),
+        });
         Some(Self {
+            current_level_stmt_addr: self.current_level_stmt_addr,
+            sandbox_goal: self.sandbox_goal.clone(),
+            library: self.library,
+            level_order: self.level_order,
+            unlocked: self.unlocked.clone(),
             proof_stack,
-            ..*self
+            next_work_var,
+            id: next_state_id(),
         })
     }
 
-    fn level_finished(&self, ctx: &Context) -> bool {
-        let db = &ctx.metamath_db;
-        let level_stmt = db.statement_by_address(self.current_level_stmt_addr);
-        let level_goal = ctx.stmt_to_formula(level_stmt);
-        if let Some(stack_last) = self.proof_stack.last() {
-            formula_eq(stack_last, &level_goal)
-        } else {
-            false
+    /// Expression the proof stack must end with
+    pub fn goal(&self, ctx: &Context) -> Expr {
+        match &self.sandbox_goal {
+            Some(goal) => goal.clone(),
+            None => ctx.level_goal(self.current_level_stmt_addr),
+        }
+    }
+
+    pub fn render_goal(&self, ctx: &Context) -> String {
+        ctx.render_expr(&self.goal(ctx))
+    }
+
+    /// Whether an assertion is an axiom or definition, or a level the player has finished
+    fn is_unlocked(&self, ctx: &Context, addr: StatementAddress) -> bool {
+        let stmt = ctx.metamath_db.statement_by_address(addr);
+        stmt.statement_type() == StatementType::Axiom || self.unlocked.contains(&addr)
+    }
+
+    /// The same state with the levels finished in `progress` unlocked
+    pub fn with_progress(&self, ctx: &Context, progress: &Progress) -> Self {
+        let unlocked = progress
+            .solved
+            .iter()
+            .filter_map(|label| ctx.statement_addr(label))
+            .collect();
+        Self {
+            unlocked: Arc::new(unlocked),
+            id: next_state_id(),
+            ..self.clone()
+        }
+    }
+
+    /// Theorems of the reference proof that are still locked in unlock mode.
+    /// If there are any, the level may be impossible to finish until they are proven.
+    pub fn locked_deps(&self, ctx: &Context) -> Vec<StatementAddress> {
+        if self.library != Library::Unlocked || self.sandbox_goal.is_some() {
+            return Vec::new();
+        }
+        ctx.reference_deps(self.current_level_stmt_addr)
+            .into_iter()
+            .filter(|&addr| {
+                let stmt = ctx.metamath_db.statement_by_address(addr);
+                stmt.statement_type() == StatementType::Provable && !self.is_unlocked(ctx, addr)
+            })
+            .collect()
+    }
+
+    /// The same state with other statements available
+    pub fn with_library(&self, library: Library) -> Self {
+        Self {
+            library,
+            id: next_state_id(),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_level_order(&self, level_order: LevelOrder) -> Self {
+        Self {
+            level_order,
+            id: next_state_id(),
+            ..self.clone()
+        }
+    }
+
+    /// Essential hypotheses of the level, of which a sandbox has none
+    fn level_hyps(&self, ctx: &Context) -> Vec<StatementAddress> {
+        match self.sandbox_goal {
+            Some(_) => Vec::new(),
+            None => ctx.essential_hyps(self.current_level_stmt_addr),
+        }
+    }
+
+    /// Whether the top entry unifies with the goal, assigning only its work variables
+    /// as mmj2 does, and the `$d`s of its proof hold once those are assigned
+    pub fn level_finished(&self, ctx: &Context) -> bool {
+        let goal = self.goal(ctx);
+        self.proof_stack.last().is_some_and(|entry| {
+            let mut substs = WorkSubsts::new();
+            if entry.expr.typecode != goal.typecode
+                || !entry
+                    .expr
+                    .tree
+                    .unify(&goal.tree, &ctx.metamath_db, &mut substs)
+            {
+                return false;
+            }
+            let dvs = index::substitute_dvs(&entry.dvs, &substs);
+            // Work variables left are exported as dummies, which no `$d` keeps apart
+            !dvs.iter().any(|(a, b)| index::dv_unresolved(a, b))
+                && ctx.dvs_hold(self.dv_level(), &dvs)
+        })
+    }
+
+    /// Proof of the finished level in reverse Polish notation, with lemmas expanded
+    pub fn export_proof(&self, ctx: &Context, lemmas: &[Lemma]) -> Option<String> {
+        if !self.level_finished(ctx) {
+            return None;
         }
+        let entry = self.proof_stack.last()?;
+        let labels = ctx.export_proof(&entry.proof, &self.goal(ctx), lemmas)?;
+        Some(labels.join(" "))
     }
 
+    /// The next level, with this one unlocked, unless in sandbox mode which has none
     pub fn next_level(&self, ctx: &Context) -> Option<Self> {
-        if self.level_finished(ctx) {
+        if self.sandbox_goal.is_none() && self.level_finished(ctx) {
+            let mut unlocked = (*self.unlocked).clone();
+            unlocked.insert(self.current_level_stmt_addr);
+            let next_addr =
+                ctx.next_level_addr(self.current_level_stmt_addr, self.level_order, &unlocked)?;
+            let next = ctx.level_state(next_addr);
             Some(State {
-                current_level_stmt_addr: ctx
-                    .metamath_db
-                    .statements_range_address(self.current_level_stmt_addr..)
-                    .skip(1)
-                    .find(|stmt| stmt.statement_type() == StatementType::Provable)
-                    .unwrap_or_else(|| {
-                        panic!("only {} statements", ctx.metamath_db.statements().count())
-                    })
-                    .address(),
-
-                proof_stack: Vec::new(),
+                library: self.library,
+                level_order: self.level_order,
+                unlocked: Arc::new(unlocked),
+                ..next
             })
         } else {
             None
         }
     }
 
-    pub fn buttons(&self, ctx: &Context) -> Vec<(StatementAddress, Option<Self>)> {
-        ctx.deps(self.current_level_stmt_addr)
+    /// Statements the player may push, then lemmas.
+    /// With an open library these are the hypotheses and assertions whose conclusion
+    /// unifies with the goal or a stack entry, as found by `search_library`.
+    fn button_steps(&self, ctx: &Context, lemmas: &[Lemma]) -> Vec<Step> {
+        let stmts = match self.library {
+            Library::Reference => ctx
+                .reference_deps(self.current_level_stmt_addr)
+                .into_iter()
+                .map(Step::Stmt)
+                .collect(),
+            Library::Open | Library::Unlocked => self
+                .search_library(ctx, lemmas, "", true)
+                .into_iter()
+                .map(|(step, _)| step)
+                .filter(|step| matches!(step, Step::Stmt(_)))
+                .collect::<Vec<_>>(),
+        };
+        stmts
             .into_iter()
-            .map(|addr| (addr, self.push(ctx, addr)))
+            .chain((0..lemmas.len()).map(Step::Lemma))
+            .collect()
+    }
+
+    /// Steps of the buttons, each with whether it can be pushed
+    pub fn buttons(&self, ctx: &Context, lemmas: &[Lemma]) -> Vec<(Step, bool)> {
+        self.buttons_with(ctx, lemmas, Context::match_hyps)
+    }
+
+    /// `buttons`, assigning stack entries to hypotheses with `match_hyps`,
+    /// for benchmarking other matchers
+    #[doc(hidden)]
+    pub fn buttons_with(
+        &self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        match_hyps: MatchHyps,
+    ) -> Vec<(Step, bool)> {
+        self.button_steps(ctx, lemmas)
+            .into_iter()
+            .map(|step| (step, self.can_push_with(ctx, lemmas, step, match_hyps)))
             .collect()
     }
 
     pub fn render_stack(&self, ctx: &Context) -> Vec<String> {
         self.proof_stack
             .iter()
-            .map(|expr| ctx.render_formula(expr))
+            .map(|entry| ctx.render_expr(&entry.expr))
             .collect()
     }
 
-    pub fn stack_swap(&self, i: usize, j: usize) -> Option<Self> {
+    /// Turn a stack entry into a lemma, with the level hypotheses its proof uses
+    pub fn lemma(&self, ctx: &Context, lemmas: &[Lemma], i: usize, name: &str) -> Option<Lemma> {
+        let db = &ctx.metamath_db;
+        let entry = self.proof_stack.get(i)?;
+        let valid_name = !name.is_empty()
+            && name
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"-_.".contains(&c));
+        if !valid_name
+            || db.statement(name.as_bytes()).is_some()
+            || lemmas.iter().any(|lemma| lemma.name == name)
+            || entry.expr.has_work_vars()
+        {
+            return None;
+        }
+        let mut hyp_addrs = Vec::new();
+        entry.proof.level_hyps(db, &mut hyp_addrs);
+        let hyp_addrs = hyp_addrs.into_iter().unique().collect();
+        Lemma::new(
+            ctx,
+            lemmas,
+            name.into(),
+            hyp_addrs,
+            Expr::clone(&entry.expr),
+            entry.proof.clone(),
+        )
+    }
+
+    /// A new state with the stack changed, sharing the entries with this one
+    fn with_stack_change(&self, change: impl FnOnce(&mut Vec<Entry>)) -> Self {
         let mut new = self.clone();
-        if new.proof_stack.get(i).is_some() && new.proof_stack.get(j).is_some() {
-            new.proof_stack.swap(i, j);
-            Some(new)
+        new.id = next_state_id();
+        change(&mut new.proof_stack);
+        new
+    }
+
+    pub fn stack_swap(&self, i: usize, j: usize) -> Option<Self> {
+        let len = self.proof_stack.len();
+        (i < len && j < len).then(|| self.with_stack_change(|stack| stack.swap(i, j)))
+    }
+
+    pub fn stack_delete(&self, i: usize) -> Option<Self> {
+        (i < self.proof_stack.len()).then(|| {
+            self.with_stack_change(|stack| {
+                stack.remove(i);
+            })
+        })
+    }
+
+    pub fn stack_copy(&self, i: usize) -> Option<Self> {
+        (i < self.proof_stack.len())
+            .then(|| self.with_stack_change(|stack| stack.insert(i, stack[i].clone())))
+    }
+
+    pub fn stack_move(&self, src: usize, dst: usize) -> Option<Self> {
+        let len = self.proof_stack.len();
+        (src < len && dst < len).then(|| {
+            self.with_stack_change(|stack| {
+                let e = stack.remove(src);
+                stack.insert(dst, e);
+            })
+        })
+    }
+
+    pub fn apply(&self, ctx: &Context, lemmas: &[Lemma], action: Action) -> Option<Self> {
+        match action {
+            Action::Push(step) => self.push(ctx, lemmas, step),
+            Action::Swap(i, j) => self.stack_swap(i, j),
+            Action::Delete(i) => self.stack_delete(i),
+            Action::Copy(i) => self.stack_copy(i),
+            Action::Move(src, dst) => self.stack_move(src, dst),
+        }
+    }
+}
+
+/// A node of the goal tree
+#[derive(Clone, Debug)]
+pub struct Goal {
+    pub expr: Expr,
+
+    /// Statement applied backward to this goal, or `None` if the goal is still open
+    pub step: Option<StatementAddress>,
+
+    /// Goals for the essential hypotheses of `step`
+    pub subgoals: Vec<Goal>,
+}
+
+impl Goal {
+    fn new(expr: Expr) -> Self {
+        Self {
+            expr,
+            step: None,
+            subgoals: Vec::new(),
+        }
+    }
+
+    fn open_goals<'a>(&'a self, out: &mut Vec<&'a Goal>) {
+        if self.step.is_none() {
+            out.push(self);
         } else {
-            None
+            for subgoal in &self.subgoals {
+                subgoal.open_goals(out);
+            }
         }
     }
 
-    pub fn stack_delete(&self, i: usize) -> Option<Self> {
-        let mut new = self.clone();
-        if new.proof_stack.get(i).is_some() {
-            new.proof_stack.remove(i);
-            Some(new)
+    fn open_goals_mut<'a>(&'a mut self, out: &mut Vec<&'a mut Goal>) {
+        if self.step.is_none() {
+            out.push(self);
         } else {
-            None
+            for subgoal in &mut self.subgoals {
+                subgoal.open_goals_mut(out);
+            }
         }
     }
 
-    pub fn stack_copy(&self, i: usize) -> Option<Self> {
-        let mut new = self.clone();
-        if new.proof_stack.get(i).is_some() {
-            new.proof_stack.insert(i, new.proof_stack[i].clone());
-            Some(new)
+    fn substitute(&mut self, substs: &WorkSubsts) {
+        self.expr = self.expr.substitute(substs);
+        for subgoal in &mut self.subgoals {
+            subgoal.substitute(substs);
+        }
+    }
+}
+
+/// The game state of goal-directed mode,
+/// where statements are applied backward starting from the level goal
+#[derive(Clone, Debug)]
+pub struct GoalTree {
+    /// Index of the statement representing the current level
+    pub current_level_stmt_addr: StatementAddress,
+
+    pub root: Goal,
+
+    /// Index of the open goal that statements are applied to
+    pub selected: usize,
+
+    /// Order of the levels `next_level` steps through
+    pub level_order: LevelOrder,
+
+    /// Levels the player has finished, like `State::unlocked`
+    unlocked: Arc<HashSet<StatementAddress>>,
+
+    /// Values the `$d`s of the applied statements keep apart, like `Entry::dvs`
+    dvs: Arc<Vec<(Tree, Tree)>>,
+
+    /// Number of the next fresh work variable
+    next_work_var: u32,
+
+    /// Identifies the tree like `State::id`
+    id: u64,
+}
+
+/// A step unified with the selected goal of a `GoalTree`
+struct GoalMatch {
+    /// Goals of its essential hypotheses
+    subgoals: Vec<Goal>,
+    substs: WorkSubsts,
+    /// `$d` value pairs of the tree once the goal is closed
+    dvs: Vec<(Tree, Tree)>,
+}
+
+impl GoalTree {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn open_goals(&self) -> Vec<&Goal> {
+        let mut out = Vec::new();
+        self.root.open_goals(&mut out);
+        out
+    }
+
+    /// Instantiate the rule of `step_addr` and unify its conclusion with the selected goal
+    fn match_goal(
+        &self,
+        ctx: &Context,
+        step_addr: StatementAddress,
+        next_work_var: &mut u32,
+    ) -> Option<GoalMatch> {
+        let db = &ctx.metamath_db;
+        let open_goals = self.open_goals();
+        let goal = &open_goals.get(self.selected)?.expr;
+        let (conclusion, subgoals, work_vars) = match ctx.rule(step_addr) {
+            Some(rule) => {
+                let (rule, work_vars) = rule.instantiate_vars(db, next_work_var);
+                let subgoals = rule
+                    .hyps
+                    .into_iter()
+                    .filter(|(essential, _)| *essential)
+                    .map(|(_, hyp)| Goal::new(hyp))
+                    .collect();
+                (rule.conclusion, subgoals, work_vars)
+            }
+            // Hypotheses close goals as-is, their variables are those of the level
+            None => (ctx.stmt_expr(step_addr)?.clone(), Vec::new(), Vec::new()),
+        };
+        let mut substs = WorkSubsts::new();
+        if goal.typecode != conclusion.typecode
+            || !goal.tree.unify(&conclusion.tree, db, &mut substs)
+        {
+            return None;
+        }
+        let mut dvs = index::substitute_dvs(&self.dvs, &substs);
+        let step_dvs = ctx.dv_values(Step::Stmt(step_addr), &[], &work_vars);
+        dvs.extend(index::substitute_dvs(&step_dvs, &substs));
+        if !ctx.dvs_hold(Some(self.current_level_stmt_addr), &dvs) {
+            return None;
+        }
+        Some(GoalMatch {
+            subgoals,
+            substs,
+            dvs,
+        })
+    }
+
+    /// Whether `step` closes the selected goal, without building the next tree
+    pub fn can_apply(&self, ctx: &Context, step: Step) -> bool {
+        let mut next_work_var = self.next_work_var;
+        matches!(step, Step::Stmt(addr) if self.match_goal(ctx, addr, &mut next_work_var).is_some())
+    }
+
+    /// Close the selected goal with `step`, opening a goal for each of its essential hypotheses
+    pub fn apply(&self, ctx: &Context, step: Step) -> Option<Self> {
+        let Step::Stmt(step_addr) = step else {
+            return None;
+        };
+        let mut next_work_var = self.next_work_var;
+        let GoalMatch {
+            subgoals,
+            substs,
+            dvs,
+        } = self.match_goal(ctx, step_addr, &mut next_work_var)?;
+        let mut root = self.root.clone();
+        let mut nodes = Vec::new();
+        root.open_goals_mut(&mut nodes);
+        let node = nodes.swap_remove(self.selected);
+        node.step = Some(step_addr);
+        node.subgoals = subgoals;
+        // Refine work variables across the whole tree
+        root.substitute(&substs);
+        Some(Self {
+            root,
+            selected: 0,
+            dvs: Arc::new(dvs),
+            next_work_var,
+            id: next_state_id(),
+            ..self.clone()
+        })
+    }
+
+    /// Whether every goal is closed, with no work variables left where a `$d` applies
+    pub fn level_finished(&self) -> bool {
+        self.open_goals().is_empty() && !self.dvs.iter().any(|(a, b)| index::dv_unresolved(a, b))
+    }
+
+    /// The next level, with this one counted as finished
+    pub fn next_level(&self, ctx: &Context) -> Option<Self> {
+        if self.level_finished() {
+            let mut unlocked = (*self.unlocked).clone();
+            unlocked.insert(self.current_level_stmt_addr);
+            let next_addr =
+                ctx.next_level_addr(self.current_level_stmt_addr, self.level_order, &unlocked)?;
+            let level = ctx.label(next_addr);
+            Some(Self {
+                level_order: self.level_order,
+                unlocked: Arc::new(unlocked),
+                ..ctx.initial_goal_tree(Some(&level))
+            })
         } else {
             None
         }
     }
 
-    pub fn stack_move(&self, src: usize, dst: usize) -> Option<Self> {
-        let mut new = self.clone();
-        if new.proof_stack.get(src).is_some() && new.proof_stack.get(dst).is_some() {
-            let e = new.proof_stack.remove(src);
-            new.proof_stack.insert(dst, e);
-            Some(new)
+    pub fn with_level_order(&self, level_order: LevelOrder) -> Self {
+        Self {
+            level_order,
+            id: next_state_id(),
+            ..self.clone()
+        }
+    }
+
+    /// The same tree with the levels finished in `progress` counted as finished
+    pub fn with_progress(&self, ctx: &Context, progress: &Progress) -> Self {
+        let unlocked = progress
+            .solved
+            .iter()
+            .filter_map(|label| ctx.statement_addr(label))
+            .collect();
+        Self {
+            unlocked: Arc::new(unlocked),
+            id: next_state_id(),
+            ..self.clone()
+        }
+    }
+
+    /// Steps of the buttons, each with whether it closes the selected goal
+    pub fn buttons(&self, ctx: &Context) -> Vec<(Step, bool)> {
+        ctx.reference_deps(self.current_level_stmt_addr)
+            .into_iter()
+            .map(|addr| (Step::Stmt(addr), self.can_apply(ctx, Step::Stmt(addr))))
+            .collect()
+    }
+
+    pub fn select(&self, i: usize) -> Option<Self> {
+        if i < self.open_goals().len() {
+            Some(Self {
+                selected: i,
+                id: next_state_id(),
+                ..self.clone()
+            })
         } else {
             None
         }
     }
+
+    pub fn render_goals(&self, ctx: &Context) -> Vec<String> {
+        self.open_goals()
+            .into_iter()
+            .map(|goal| ctx.render_expr(&goal.expr))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{expr::tests::context, Action, Step};
+
+    #[test]
+    fn level_finished_assigns_work_variables() {
+        let ctx = context();
+        let ax1 = ctx.metamath_db.statement(b"ax-1").unwrap().address();
+        let push_ax1 = |level| {
+            ctx.initial_state(Some(level))
+                .apply(&ctx, &[], Action::Push(Step::Stmt(ax1)))
+                .unwrap()
+        };
+        assert!(push_ax1("a1").level_finished(&ctx));
+        assert!(!push_ax1("a1c").level_finished(&ctx));
+    }
 }
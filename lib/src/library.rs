@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+
+use metamath_rs::{formula::Label, statement::StatementAddress, StatementType};
+
+use crate::{Context, Expr, Lemma, Rule, State, Step, Tree, WorkSubsts};
+
+/// Bound on the statements a library search returns
+const MAX_SEARCH_RESULTS: usize = 100;
+
+/// Which statements the player may push
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Library {
+    /// Only the statements used by the reference proof of the level
+    #[default]
+    Reference,
+    /// Every assertion earlier in the database than the level
+    Open,
+    /// Axioms, definitions and the levels the player has finished
+    Unlocked,
+}
+
+impl Library {
+    pub const ALL: [Self; 3] = [Self::Reference, Self::Open, Self::Unlocked];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Reference => "Reference proof's statements",
+            Self::Open => "Open library",
+            Self::Unlocked => "Unlocked theorems only",
+        }
+    }
+}
+
+/// An assertion with a provable conclusion
+struct IndexEntry {
+    addr: StatementAddress,
+    /// Position of the statement in the database
+    ordinal: usize,
+    label: String,
+    /// Lowercase text of the comment preceding the statement
+    comment: String,
+}
+
+/// Assertions of the database, for searching them without pushing each one
+pub(crate) struct LibraryIndex {
+    entries: Vec<IndexEntry>,
+    /// Position in the database of every statement
+    ordinals: HashMap<StatementAddress, usize>,
+    /// Index into `entries` of every assertion
+    positions: HashMap<StatementAddress, usize>,
+    /// Entries by the syntax axiom at the root of their conclusion,
+    /// `None` for conclusions which are a single variable
+    by_head: HashMap<Option<Label>, Vec<usize>>,
+    /// Entries with essential hypotheses by the syntax axiom at the root of the last one,
+    /// which takes the top of the stack when pushing them
+    by_last_hyp: HashMap<Option<Label>, Vec<usize>>,
+}
+
+fn head(tree: &Tree) -> Option<Label> {
+    match tree {
+        Tree::App(label, _) => Some(*label),
+        _ => None,
+    }
+}
+
+/// Cheap check ruling out most trees that don't unify
+fn may_unify(a: &Tree, b: &Tree) -> bool {
+    match (head(a), head(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
+}
+
+/// Cheap check that an instance of `pattern`, whose variables may take any value,
+/// could equal `tree`, whose work variables may take any value
+pub(crate) fn may_match(pattern: &Tree, tree: &Tree) -> bool {
+    match (pattern, tree) {
+        (Tree::Var(_) | Tree::Work(_, _), _) | (_, Tree::Work(_, _)) => true,
+        (Tree::App(a, a_args), Tree::App(b, b_args)) => {
+            a == b && a_args.iter().zip(b_args).all(|(a, b)| may_match(a, b))
+        }
+        (Tree::App(_, _), Tree::Var(_)) => false,
+    }
+}
+
+/// Entries of an index by head whose tree may unify with `tree`, in database order
+fn lookup(by_head: &HashMap<Option<Label>, Vec<usize>>, tree: &Tree) -> Vec<usize> {
+    let mut out = match tree {
+        Tree::App(label, _) => {
+            let mut out = by_head.get(&Some(*label)).cloned().unwrap_or_default();
+            out.extend(by_head.get(&None).into_iter().flatten());
+            out
+        }
+        _ => by_head.values().flatten().copied().collect(),
+    };
+    out.sort_unstable();
+    out
+}
+
+/// Which part of an assertion matched a search pattern
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matched {
+    Conclusion,
+    /// Index among the essential hypotheses
+    Hyp(usize),
+}
+
+/// An assertion found by `Context::search`
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub addr: StatementAddress,
+    pub matched: Matched,
+
+    /// Rendered variables of the assertion with the rendered expressions
+    /// they take to match the pattern, leaving out unconstrained variables
+    pub substs: Vec<(String, String)>,
+}
+
+impl LibraryIndex {
+    fn new(ctx: &Context) -> Self {
+        let db = &ctx.metamath_db;
+        let names = db.name_result();
+        let provable_typecode = db.grammar_result().provable_typecode();
+        let mut entries = Vec::new();
+        let mut ordinals = HashMap::new();
+        let mut positions = HashMap::new();
+        let mut by_head = HashMap::<_, Vec<_>>::new();
+        let mut by_last_hyp = HashMap::<_, Vec<_>>::new();
+        for (ordinal, stmt) in db.statements().enumerate() {
+            ordinals.insert(stmt.address(), ordinal);
+            let is_assertion = matches!(
+                stmt.statement_type(),
+                StatementType::Axiom | StatementType::Provable
+            );
+            if !is_assertion || names.get_atom(stmt.math_at(0).slice) != provable_typecode {
+                continue;
+            }
+            let comment = stmt.associated_comment().map_or(String::new(), |comment| {
+                let (buf, span) = comment.comment_contents();
+                String::from_utf8_lossy(span.as_ref(buf)).to_lowercase()
+            });
+            let Some(rule) = ctx.rule(stmt.address()) else {
+                continue;
+            };
+            by_head
+                .entry(head(&rule.conclusion.tree))
+                .or_default()
+                .push(entries.len());
+            if let Some((_, hyp)) = rule.hyps.iter().rev().find(|(essential, _)| *essential) {
+                by_last_hyp
+                    .entry(head(&hyp.tree))
+                    .or_default()
+                    .push(entries.len());
+            }
+            positions.insert(stmt.address(), entries.len());
+            entries.push(IndexEntry {
+                addr: stmt.address(),
+                ordinal,
+                label: crate::from_utf8(stmt.label()),
+                comment,
+            });
+        }
+        Self {
+            entries,
+            ordinals,
+            positions,
+            by_head,
+            by_last_hyp,
+        }
+    }
+
+    /// Entries whose conclusion may unify with `tree`
+    fn candidates(&self, tree: &Tree) -> Vec<usize> {
+        lookup(&self.by_head, tree)
+    }
+}
+
+impl Context {
+    pub(crate) fn library_index(&self) -> &LibraryIndex {
+        self.library_index.get_or_init(|| LibraryIndex::new(self))
+    }
+
+    /// Whether statement `a` comes before statement `b` in the database
+    pub(crate) fn precedes(&self, a: StatementAddress, b: StatementAddress) -> bool {
+        let ordinals = &self.library_index().ordinals;
+        ordinals[&a] < ordinals[&b]
+    }
+
+    /// Whether some instance of `conclusion` unifies with `expr`
+    fn conclusion_unifies(&self, conclusion: &Expr, expr: &Expr, next_work_var: u32) -> bool {
+        let db = &self.metamath_db;
+        let rule = Rule {
+            hyps: Vec::new(),
+            conclusion: conclusion.clone(),
+        };
+        let conclusion = rule.instantiate(db, &mut { next_work_var }).conclusion;
+        conclusion.typecode == expr.typecode
+            && conclusion
+                .tree
+                .unify(&expr.tree, db, &mut WorkSubsts::new())
+    }
+
+    /// Every assertion whose conclusion or one of whose essential hypotheses
+    /// unifies with `pattern`, in database order
+    pub fn search(&self, pattern: &Expr) -> Vec<SearchResult> {
+        self.search_entries(pattern)
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect()
+    }
+
+    /// Like `search`, with the position in the database of each result
+    fn search_entries(&self, pattern: &Expr) -> Vec<(usize, SearchResult)> {
+        let db = &self.metamath_db;
+        let index = self.library_index();
+        // Work variables of the assertion must be distinct from those of the pattern
+        let first_work_var = pattern.tree.max_work_var().map_or(0, |n| n + 1);
+        let mut out = Vec::new();
+        for entry in &index.entries {
+            let entry_rule = self.rule(entry.addr).unwrap();
+            let essential_hyps = entry_rule.hyps.iter().filter(|(essential, _)| *essential);
+            let parts = [(Matched::Conclusion, &entry_rule.conclusion)]
+                .into_iter()
+                .chain(
+                    essential_hyps
+                        .enumerate()
+                        .map(|(i, (_, hyp))| (Matched::Hyp(i), hyp)),
+                );
+            for (matched, expr) in parts {
+                if expr.typecode != pattern.typecode || !may_unify(&expr.tree, &pattern.tree) {
+                    continue;
+                }
+                let (rule, work_vars) = entry_rule.instantiate_vars(db, &mut { first_work_var });
+                let instance = match matched {
+                    Matched::Conclusion => &rule.conclusion,
+                    Matched::Hyp(i) => {
+                        &rule
+                            .hyps
+                            .iter()
+                            .filter(|(essential, _)| *essential)
+                            .nth(i)
+                            .unwrap()
+                            .1
+                    }
+                };
+                let mut substs = WorkSubsts::new();
+                if !instance.tree.unify(&pattern.tree, db, &mut substs) {
+                    continue;
+                }
+                let substs = work_vars
+                    .iter()
+                    .filter_map(|(label, work_var)| {
+                        let value = work_var.substitute(&substs);
+                        (value != *work_var).then(|| {
+                            (
+                                self.render_tree(&Tree::Var(*label)),
+                                self.render_tree(&value),
+                            )
+                        })
+                    })
+                    .collect();
+                let result = SearchResult {
+                    addr: entry.addr,
+                    matched,
+                    substs,
+                };
+                out.push((entry.ordinal, result));
+            }
+        }
+        out
+    }
+}
+
+impl State {
+    /// Whether the player may push a database statement
+    pub(crate) fn may_push(&self, ctx: &Context, addr: StatementAddress) -> bool {
+        match self.library {
+            Library::Reference => ctx
+                .reference_deps(self.current_level_stmt_addr)
+                .contains(&addr),
+            Library::Open | Library::Unlocked => {
+                let index = ctx.library_index();
+                let end = index.ordinals[&self.current_level_stmt_addr];
+                let available = index
+                    .positions
+                    .get(&addr)
+                    .is_some_and(|&i| index.entries[i].ordinal < end)
+                    && (self.library != Library::Unlocked || self.is_unlocked(ctx, addr));
+                available || self.level_hyps(ctx).contains(&addr)
+            }
+        }
+    }
+
+    /// Statements the player may push and lemmas whose last essential hypothesis
+    /// may take `expr`, so that pushing them would consume it.
+    /// Open-library and unlock mode look them up in the library index.
+    pub(crate) fn consumers(&self, ctx: &Context, lemmas: &[Lemma], expr: &Expr) -> Vec<Step> {
+        let stmts = match self.library {
+            Library::Reference => ctx.reference_deps(self.current_level_stmt_addr),
+            Library::Open | Library::Unlocked => {
+                let index = ctx.library_index();
+                let end = index.ordinals[&self.current_level_stmt_addr];
+                lookup(&index.by_last_hyp, &expr.tree)
+                    .into_iter()
+                    .map(|i| &index.entries[i])
+                    .filter(|entry| {
+                        entry.ordinal < end
+                            && (self.library != Library::Unlocked
+                                || self.is_unlocked(ctx, entry.addr))
+                    })
+                    .map(|entry| entry.addr)
+                    .collect()
+            }
+        };
+        stmts
+            .into_iter()
+            .map(Step::Stmt)
+            .chain((0..lemmas.len()).map(Step::Lemma))
+            .filter(|&step| {
+                let Some(rule) = ctx.step_rule(step, lemmas) else {
+                    return false;
+                };
+                rule.hyps
+                    .iter()
+                    .rev()
+                    .find(|(essential, _)| *essential)
+                    .is_some_and(|(_, hyp)| {
+                        hyp.typecode == expr.typecode && may_match(&hyp.tree, &expr.tree)
+                    })
+            })
+            .collect()
+    }
+
+    /// Assertions earlier than the level with an essential hypothesis matching stack entry `i`,
+    /// answering "what can I do with this?"
+    pub fn search_uses(&self, ctx: &Context, i: usize) -> Vec<SearchResult> {
+        let Some(entry) = self.proof_stack.get(i) else {
+            return Vec::new();
+        };
+        self.search_before_level(ctx, &entry.expr, |matched| matched != Matched::Conclusion)
+    }
+
+    /// Assertions earlier than the level with a conclusion matching the goal,
+    /// answering "what could produce this?"
+    pub fn search_producers(&self, ctx: &Context) -> Vec<SearchResult> {
+        self.search_before_level(ctx, &self.goal(ctx), |matched| {
+            matched == Matched::Conclusion
+        })
+    }
+
+    fn search_before_level(
+        &self,
+        ctx: &Context,
+        pattern: &Expr,
+        keep: impl Fn(Matched) -> bool,
+    ) -> Vec<SearchResult> {
+        let end = ctx.library_index().ordinals[&self.current_level_stmt_addr];
+        ctx.search_entries(pattern)
+            .into_iter()
+            .filter(|(ordinal, result)| *ordinal < end && keep(result.matched))
+            .map(|(_, result)| result)
+            .take(MAX_SEARCH_RESULTS)
+            .collect()
+    }
+
+    /// Buttons for the statements available in open-library or unlock mode and the lemmas
+    /// whose label or comment contains `text`, ignoring case.
+    /// Hypotheses of the level are only filtered by label.
+    /// With `unifiable`, only statements whose conclusion unifies
+    /// with the goal or a stack entry are kept.
+    pub fn search_library(
+        &self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        text: &str,
+        unifiable: bool,
+    ) -> Vec<(Step, bool)> {
+        let index = ctx.library_index();
+        let end = index.ordinals[&self.current_level_stmt_addr];
+        let text = text.to_lowercase();
+        let targets = [self.goal(ctx)]
+            .into_iter()
+            .chain(
+                self.proof_stack
+                    .iter()
+                    .map(|entry| Expr::clone(&entry.expr)),
+            )
+            .collect::<Vec<_>>();
+        let matches = |i: usize| {
+            let entry = &index.entries[i];
+            entry.ordinal < end
+                && (self.library != Library::Unlocked || self.is_unlocked(ctx, entry.addr))
+                && (entry.label.to_lowercase().contains(&text) || entry.comment.contains(&text))
+        };
+        let unifies_with_target = |conclusion: &Expr| {
+            targets
+                .iter()
+                .any(|target| ctx.conclusion_unifies(conclusion, target, self.next_work_var))
+        };
+        let found: Vec<usize> = if unifiable {
+            let mut found = targets
+                .iter()
+                .flat_map(|target| {
+                    index
+                        .candidates(&target.tree)
+                        .into_iter()
+                        .filter(|&i| matches(i))
+                        .filter(|&i| {
+                            let conclusion = &ctx.rule(index.entries[i].addr).unwrap().conclusion;
+                            ctx.conclusion_unifies(conclusion, target, self.next_work_var)
+                        })
+                })
+                .collect::<Vec<_>>();
+            found.sort_unstable();
+            found.dedup();
+            found
+        } else {
+            (0..index.entries.len()).filter(|&i| matches(i)).collect()
+        };
+        let found_lemmas = lemmas
+            .iter()
+            .enumerate()
+            .filter(|(_, lemma)| lemma.name.to_lowercase().contains(&text))
+            .filter(|(_, lemma)| !unifiable || unifies_with_target(&lemma.rule.conclusion))
+            .map(|(i, _)| Step::Lemma(i));
+        // Hypotheses of the level come first, as every proof of it may need them
+        let found_hyps = self
+            .level_hyps(ctx)
+            .into_iter()
+            .filter(|&addr| ctx.label(addr).to_lowercase().contains(&text))
+            .map(Step::Stmt);
+        found_hyps
+            .chain(found.into_iter().map(|i| Step::Stmt(index.entries[i].addr)))
+            .chain(found_lemmas)
+            .take(MAX_SEARCH_RESULTS)
+            .map(|step| (step, self.can_push(ctx, lemmas, step)))
+            .collect()
+    }
+}
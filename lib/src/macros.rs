@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Action, Context, Lemma, State, Step};
+
+/// A recorded sequence of actions, replayable in other states
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Macro {
+    pub name: String,
+
+    /// Labels pushed while recording, which replays may replace
+    pub params: Vec<String>,
+
+    pub actions: Vec<MacroAction>,
+}
+
+/// An action with stack positions counted from the top, 0 being the top entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MacroAction {
+    /// Push the step given for a parameter
+    Push(usize),
+    Swap(usize, usize),
+    Delete(usize),
+    Copy(usize),
+    Move(usize, usize),
+}
+
+impl Macro {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Record `action`, taken in `state`
+    pub fn record(&mut self, ctx: &Context, lemmas: &[Lemma], state: &State, action: Action) {
+        let len = state.proof_stack.len();
+        let from_top = |i: usize| len - 1 - i;
+        let action = match action {
+            Action::Push(step) => {
+                let label = ctx.step_label(step, lemmas);
+                match self.params.iter().position(|param| *param == label) {
+                    Some(param) => MacroAction::Push(param),
+                    None => {
+                        self.params.push(label);
+                        MacroAction::Push(self.params.len() - 1)
+                    }
+                }
+            }
+            Action::Swap(i, j) => MacroAction::Swap(from_top(i), from_top(j)),
+            Action::Delete(i) => MacroAction::Delete(from_top(i)),
+            Action::Copy(i) => MacroAction::Copy(from_top(i)),
+            Action::Move(src, dst) => MacroAction::Move(from_top(src), from_top(dst)),
+        };
+        self.actions.push(action);
+    }
+}
+
+impl State {
+    /// Replay a macro, pushing the steps labeled `args` in place of its parameters.
+    /// Only the level's dependencies and the player's lemmas may be pushed.
+    pub fn run_macro(
+        &self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        mac: &Macro,
+        args: &[String],
+    ) -> Option<Self> {
+        if args.len() != mac.params.len() {
+            return None;
+        }
+        let args = args
+            .iter()
+            .map(|label| match ctx.step_by_label(label, lemmas)? {
+                Step::Stmt(addr) if !self.may_push(ctx, addr) => None,
+                step => Some(step),
+            })
+            .collect::<Option<Vec<_>>>()?;
+        mac.actions.iter().try_fold(self.clone(), |state, action| {
+            let len = state.proof_stack.len();
+            let from_top = |i: usize| len.checked_sub(i + 1);
+            let action = match *action {
+                MacroAction::Push(param) => Action::Push(args[param]),
+                MacroAction::Swap(i, j) => Action::Swap(from_top(i)?, from_top(j)?),
+                MacroAction::Delete(i) => Action::Delete(from_top(i)?),
+                MacroAction::Copy(i) => Action::Copy(from_top(i)?),
+                MacroAction::Move(src, dst) => Action::Move(from_top(src)?, from_top(dst)?),
+            };
+            state.apply(ctx, lemmas, action)
+        })
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Context, StatementAddress};
+
+/// A level of a level pack, with its commentary
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManifestLevel {
+    /// Label of the provable statement to prove
+    pub label: String,
+    pub title: Option<String>,
+    /// Shown when the level starts
+    pub intro: String,
+    /// Revealed one at a time on request
+    pub hints: Vec<String>,
+    /// Number of pushes of a good proof
+    pub par: Option<usize>,
+    /// Labels of the statements available in reference mode,
+    /// instead of those of the reference proof
+    pub deps: Option<Vec<String>>,
+    /// Labels of the statements pushed when the level starts
+    pub start_stack: Vec<String>,
+}
+
+/// An ordered list of levels, which level stepping follows instead of the database order
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    pub title: String,
+    /// Path of the database file, relative to the manifest
+    pub database: String,
+    pub levels: Vec<ManifestLevel>,
+}
+
+impl Manifest {
+    pub fn from_json(text: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Why a manifest doesn't fit the database
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestError {
+    /// Index of the offending level
+    pub level: usize,
+    pub label: String,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "level {}: {} {}", self.level, self.message, self.label)
+    }
+}
+
+/// A manifest with its labels resolved
+pub(crate) struct LevelPack {
+    manifest: Manifest,
+    addrs: Vec<StatementAddress>,
+    /// Index of each level
+    positions: HashMap<StatementAddress, usize>,
+    deps: Vec<Option<Vec<StatementAddress>>>,
+    start_stacks: Vec<Vec<StatementAddress>>,
+}
+
+impl LevelPack {
+    pub(crate) fn levels(&self) -> &[StatementAddress] {
+        &self.addrs
+    }
+
+    pub(crate) fn first(&self) -> Option<StatementAddress> {
+        self.addrs.first().copied()
+    }
+
+    pub(crate) fn next(&self, addr: StatementAddress) -> Option<StatementAddress> {
+        self.addrs.get(self.positions.get(&addr)? + 1).copied()
+    }
+
+    pub(crate) fn start_stack(&self, addr: StatementAddress) -> &[StatementAddress] {
+        match self.positions.get(&addr) {
+            Some(&i) => &self.start_stacks[i],
+            None => &[],
+        }
+    }
+}
+
+impl Context {
+    /// Follow `manifest` for the order, dependencies and start stacks of levels
+    pub fn set_manifest(&mut self, manifest: Manifest) -> Result<(), ManifestError> {
+        let mut pack = LevelPack {
+            manifest: Manifest::default(),
+            addrs: Vec::new(),
+            positions: HashMap::new(),
+            deps: Vec::new(),
+            start_stacks: Vec::new(),
+        };
+        for (i, level) in manifest.levels.iter().enumerate() {
+            let addr = |label: &String| {
+                self.statement_addr(label).ok_or_else(|| ManifestError {
+                    level: i,
+                    label: label.clone(),
+                    message: "unknown statement",
+                })
+            };
+            let level_addr = addr(&level.label)?;
+            let error = |message| ManifestError {
+                level: i,
+                label: level.label.clone(),
+                message,
+            };
+            if !self.is_level(&self.metamath_db.statement_by_address(level_addr)) {
+                return Err(error("not a level"));
+            }
+            if pack.positions.insert(level_addr, i).is_some() {
+                return Err(error("duplicate level"));
+            }
+            pack.addrs.push(level_addr);
+            pack.deps.push(match &level.deps {
+                Some(deps) => Some(deps.iter().map(addr).collect::<Result<_, _>>()?),
+                None => None,
+            });
+            pack.start_stacks.push(
+                level
+                    .start_stack
+                    .iter()
+                    .map(addr)
+                    .collect::<Result<_, _>>()?,
+            );
+        }
+        let previous = self.level_pack.replace(pack);
+        let pack = self.level_pack.as_ref().unwrap();
+        let unpushable = pack.addrs.iter().enumerate().find_map(|(i, &addr)| {
+            let entry = self.start_state(addr).err()?;
+            Some(ManifestError {
+                level: i,
+                label: manifest.levels[i].start_stack[entry].clone(),
+                message: "cannot push the start stack entry",
+            })
+        });
+        if let Some(error) = unpushable {
+            self.level_pack = previous;
+            return Err(error);
+        }
+        self.level_pack.as_mut().unwrap().manifest = manifest;
+        Ok(())
+    }
+
+    pub fn manifest(&self) -> Option<&Manifest> {
+        Some(&self.level_pack.as_ref()?.manifest)
+    }
+
+    /// Commentary of a level of the manifest
+    pub fn manifest_level(&self, addr: StatementAddress) -> Option<&ManifestLevel> {
+        let pack = self.level_pack.as_ref()?;
+        Some(&pack.manifest.levels[*pack.positions.get(&addr)?])
+    }
+
+    /// Statements available for a level in reference mode, following the manifest
+    pub(crate) fn reference_deps(&self, addr: StatementAddress) -> Vec<StatementAddress> {
+        let deps = self
+            .level_pack
+            .as_ref()
+            .and_then(|pack| pack.deps[*pack.positions.get(&addr)?].as_ref());
+        match deps {
+            Some(deps) => {
+                // The hypotheses of the level are always available
+                let mut out = self.essential_hyps(addr);
+                out.extend(deps);
+                out
+            }
+            None => self.deps(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, ManifestLevel};
+    use crate::{expr::tests, Context, LevelFilter};
+
+    /// The test database, whose proofs are all incomplete
+    fn context() -> Context {
+        tests::context().with_level_filter(LevelFilter {
+            skip_incomplete: false,
+            ..Default::default()
+        })
+    }
+
+    fn manifest(start_stack: &[&str]) -> Manifest {
+        Manifest {
+            levels: vec![ManifestLevel {
+                label: "mp2".into(),
+                start_stack: start_stack.iter().map(|label| label.to_string()).collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn levels(labels: &[&str]) -> Manifest {
+        Manifest {
+            levels: labels
+                .iter()
+                .map(|label| ManifestLevel {
+                    label: label.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn levels_must_be_provable_statements() {
+        let mut ctx = context();
+        let error = ctx.set_manifest(levels(&["mp2", "ax-mp"])).unwrap_err();
+        assert_eq!((error.level, error.label.as_str()), (1, "ax-mp"));
+        assert_eq!(error.message, "not a level");
+        assert!(ctx.manifest().is_none());
+    }
+
+    #[test]
+    fn levels_must_be_distinct() {
+        let mut ctx = context();
+        let error = ctx
+            .set_manifest(levels(&["mp2", "mp1", "mp2"]))
+            .unwrap_err();
+        assert_eq!((error.level, error.label.as_str()), (2, "mp2"));
+        assert_eq!(error.message, "duplicate level");
+    }
+
+    #[test]
+    fn start_stacks_may_consume_entries() {
+        let mut ctx = context();
+        ctx.set_manifest(manifest(&["mp2.1", "mp2.2", "mp1"]))
+            .unwrap();
+        assert_eq!(ctx.initial_state(None).proof_stack.len(), 1);
+        let error = ctx.set_manifest(manifest(&["mp2.2", "mp1"])).unwrap_err();
+        assert_eq!(error.label, "mp1");
+        // The previous manifest is kept
+        assert_eq!(ctx.initial_state(None).proof_stack.len(), 1);
+    }
+}
@@ -0,0 +1,148 @@
+use metamath_rs::statement::TokenPtr;
+
+use crate::{
+    expr::{syntax_parts, SyntaxPart},
+    from_utf8, Context, Expr, Tree,
+};
+
+/// Text of an althtml definition without its tags, keeping character references
+fn strip_tags(html: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out.trim().to_string()
+}
+
+/// Color of the `color:` style of an althtml definition, as set.mm colors variables by type
+fn style_color(html: &str) -> Option<&str> {
+    let start = html.find("color:")? + "color:".len();
+    let color = html[start..].split(['"', '\'', ';']).next()?.trim();
+    (!color.is_empty()).then_some(color)
+}
+
+/// `<math>` element around rendered elements
+pub(crate) fn math_element(elements: &str) -> String {
+    format!("<math display=\"inline\"><mrow>{elements}</mrow></math>")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Context {
+    /// `<mn>` for numbers, `<mi>` for names and `<mo>` for other symbols,
+    /// showing the text of the althtml definition
+    pub(crate) fn mathml_token(&self, token: TokenPtr) -> String {
+        let tag = if token.iter().all(u8::is_ascii_digit) {
+            "mn"
+        } else if token.iter().all(u8::is_ascii_alphanumeric) {
+            "mi"
+        } else {
+            "mo"
+        };
+        let typesetting_data = self.metamath_db.typesetting_result();
+        let html = typesetting_data
+            .alt_html_defs
+            .get(token)
+            .map(|(_, _, html)| from_utf8(html));
+        let text = html.as_deref().map(strip_tags).unwrap_or_default();
+        let text = if text.is_empty() {
+            escape(&from_utf8(token))
+        } else {
+            text
+        };
+        match html.as_deref().and_then(style_color) {
+            Some(color) => format!("<{tag} mathcolor=\"{color}\">{text}</{tag}>"),
+            None => format!("<{tag}>{text}</{tag}>"),
+        }
+    }
+
+    /// Nested MathML of a tree, with an `<mrow>` for each syntax axiom
+    fn tree_to_mathml(&self, tree: &Tree, out: &mut String) {
+        let db = &self.metamath_db;
+        match tree {
+            Tree::Var(label) => {
+                let stmt = db.statement_by_label(*label).unwrap();
+                out.push_str(&self.mathml_token(stmt.math_at(1).slice));
+            }
+            Tree::Work(typecode, n) => out.push_str(&self.render_work_var(*typecode, *n)),
+            Tree::App(label, args) => {
+                out.push_str("<mrow>");
+                for part in syntax_parts(db, *label) {
+                    match part {
+                        SyntaxPart::Symbol(tok) => out.push_str(&self.mathml_token(tok)),
+                        SyntaxPart::Arg(i) => self.tree_to_mathml(&args[i], out),
+                    }
+                }
+                out.push_str("</mrow>");
+            }
+        }
+    }
+
+    pub(crate) fn tree_mathml(&self, tree: &Tree) -> String {
+        let mut out = String::new();
+        self.tree_to_mathml(tree, &mut out);
+        math_element(&out)
+    }
+
+    pub(crate) fn expr_mathml(&self, expr: &Expr) -> String {
+        let names = self.metamath_db.name_result();
+        let mut out = self.mathml_token(names.atom_name(expr.typecode));
+        self.tree_to_mathml(&expr.tree, &mut out);
+        math_element(&out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Context, TypesetMode};
+
+    /// Variables colored like set.mm, and symbols which need escaping
+    const DB: &str = r#"
+        $( $j syntax 'wff'; syntax '|-' as 'wff'; $)
+        $c ( ) -> < & wff |- $.
+        $v ph ps $.
+        $( $t
+            althtmldef "ph" as '<i><span style="color:blue">&#x1D711;</span></i>';
+            althtmldef "->" as ' &rarr; ';
+        $)
+        wph $f wff ph $.
+        wps $f wff ps $.
+        wi $a wff ( ph -> ps ) $.
+        wlt $a wff ( ph < ps ) $.
+        wand $a wff ( ph & ps ) $.
+    "#;
+
+    fn mathml(text: &str) -> String {
+        let ctx = Context::load("test.mm", DB, TypesetMode::MathML);
+        ctx.tree_mathml(&ctx.parse_expr(text).unwrap().tree)
+    }
+
+    #[test]
+    fn syntax_axioms_nest_rows() {
+        let ph = r#"<mi mathcolor="blue">&#x1D711;</mi>"#;
+        let inner = format!("<mrow><mo>(</mo><mi>ps</mi><mo>&rarr;</mo>{ph}<mo>)</mo></mrow>");
+        assert_eq!(
+            mathml("|- ( ph -> ( ps -> ph ) )"),
+            format!(
+                "<math display=\"inline\"><mrow><mrow><mo>(</mo>{ph}<mo>&rarr;</mo>{inner}\
+                 <mo>)</mo></mrow></mrow></math>"
+            )
+        );
+    }
+
+    #[test]
+    fn token_text_is_escaped() {
+        assert!(mathml("|- ( ph < ps )").contains("<mo>&lt;</mo>"));
+        assert!(mathml("|- ( ph & ps )").contains("<mo>&amp;</mo>"));
+    }
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Macro, SavedLemma};
+
+/// Everything about the player that is kept between sessions
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Progress {
+    pub lemmas: Vec<SavedLemma>,
+    pub macros: Vec<Macro>,
+
+    /// Labels of the levels the player has finished
+    pub solved: Vec<String>,
+}
+
+impl Progress {
+    pub fn from_json(text: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn record_solved(&mut self, label: String) {
+        if !self.solved.contains(&label) {
+            self.solved.push(label);
+        }
+    }
+}
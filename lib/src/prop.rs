@@ -0,0 +1,122 @@
+use metamath_rs::formula::Label;
+
+use crate::{Context, Expr, Tree};
+
+/// Truth tables of more variables are not computed
+const MAX_VARS: usize = 16;
+
+/// A formula of propositional logic, with variables numbered by first appearance
+#[derive(Clone, Debug)]
+enum Prop {
+    Var(usize),
+    True,
+    False,
+    Not(Box<Prop>),
+    Imp(Box<Prop>, Box<Prop>),
+    And(Box<Prop>, Box<Prop>),
+    Or(Box<Prop>, Box<Prop>),
+    Iff(Box<Prop>, Box<Prop>),
+}
+
+impl Prop {
+    fn eval(&self, assignment: &[bool]) -> bool {
+        match self {
+            Self::Var(i) => assignment[*i],
+            Self::True => true,
+            Self::False => false,
+            Self::Not(a) => !a.eval(assignment),
+            Self::Imp(a, b) => !a.eval(assignment) || b.eval(assignment),
+            Self::And(a, b) => a.eval(assignment) && b.eval(assignment),
+            Self::Or(a, b) => a.eval(assignment) || b.eval(assignment),
+            Self::Iff(a, b) => a.eval(assignment) == b.eval(assignment),
+        }
+    }
+}
+
+/// Truth table of a propositional formula
+#[derive(Clone, Debug)]
+pub struct TruthTable {
+    /// Math tokens of the variables, in order of first appearance
+    pub vars: Vec<String>,
+
+    /// Each assignment of the variables, with the value of the formula
+    pub rows: Vec<(Vec<bool>, bool)>,
+}
+
+impl TruthTable {
+    pub fn is_tautology(&self) -> bool {
+        self.rows.iter().all(|(_, value)| *value)
+    }
+
+    /// An assignment making the formula false
+    pub fn falsifying_assignment(&self) -> Option<&[bool]> {
+        self.rows
+            .iter()
+            .find(|(_, value)| !value)
+            .map(|(assignment, _)| assignment.as_slice())
+    }
+}
+
+fn var_index(vars: &mut Vec<Tree>, var: &Tree) -> usize {
+    match vars.iter().position(|v| v == var) {
+        Some(i) => i,
+        None => {
+            vars.push(var.clone());
+            vars.len() - 1
+        }
+    }
+}
+
+impl Context {
+    /// Convert a tree built only from propositional connectives over wff variables
+    fn tree_to_prop(&self, tree: &Tree, vars: &mut Vec<Tree>) -> Option<Prop> {
+        let db = &self.metamath_db;
+        match tree {
+            Tree::Var(_) | Tree::Work(_, _) => (tree.typecode(db) == self.logic_typecode())
+                .then(|| Prop::Var(var_index(vars, tree))),
+            Tree::App(label, args) => {
+                let mut arg = |i: usize| Some(Box::new(self.tree_to_prop(&args[i], vars)?));
+                let prop = match self.syntax_label(*label) {
+                    b"wtru" => Prop::True,
+                    b"wfal" => Prop::False,
+                    b"wn" => Prop::Not(arg(0)?),
+                    b"wi" => Prop::Imp(arg(0)?, arg(1)?),
+                    b"wa" => Prop::And(arg(0)?, arg(1)?),
+                    b"wo" => Prop::Or(arg(0)?, arg(1)?),
+                    b"wb" => Prop::Iff(arg(0)?, arg(1)?),
+                    _ => return None,
+                };
+                Some(prop)
+            }
+        }
+    }
+
+    pub(crate) fn syntax_label(&self, label: Label) -> &[u8] {
+        self.metamath_db.statement_by_label(label).unwrap().label()
+    }
+
+    /// Truth table of a provable expression that is purely propositional
+    pub fn truth_table(&self, expr: &Expr) -> Option<TruthTable> {
+        let db = &self.metamath_db;
+        if expr.typecode != db.grammar_result().provable_typecode() {
+            return None;
+        }
+        let mut vars = Vec::new();
+        let prop = self.tree_to_prop(&expr.tree, &mut vars)?;
+        if vars.len() > MAX_VARS {
+            return None;
+        }
+        let rows = (0..1u32 << vars.len())
+            .map(|bits| {
+                // The first variable changes slowest, as in textbook tables
+                let assignment = (0..vars.len())
+                    .map(|i| bits & (1 << (vars.len() - 1 - i)) == 0)
+                    .collect::<Vec<_>>();
+                let value = prop.eval(&assignment);
+                (assignment, value)
+            })
+            .collect();
+        let vars = vars.iter().map(|var| self.tree_to_ascii(var)).collect();
+        Some(TruthTable { vars, rows })
+    }
+}
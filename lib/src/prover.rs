@@ -0,0 +1,443 @@
+use std::{collections::HashMap, rc::Rc};
+
+use metamath_rs::formula::Label;
+
+use crate::{Action, Context, Lemma, State, StatementAddress, Step, Tree};
+
+/// Labels of the propositional axioms, modus ponens first
+const AXIOMS: [&[u8]; 4] = [b"ax-mp", b"ax-1", b"ax-2", b"ax-3"];
+
+/// Definitions of the connectives other than negation and implication,
+/// by the syntax axiom they define
+const DEFINITIONS: [(&[u8], &[u8]); 5] = [
+    (b"wb", b"df-bi"),
+    (b"wa", b"df-an"),
+    (b"wo", b"df-or"),
+    (b"wtru", b"df-tru"),
+    (b"wfal", b"df-fal"),
+];
+
+/// A formula built from negation and implication over atoms,
+/// which stand for any other subformula
+enum Skeleton {
+    Atom(usize),
+    Not(Box<Skeleton>),
+    Imp(Box<Skeleton>, Box<Skeleton>),
+}
+
+impl Skeleton {
+    fn eval(&self, assignment: &[bool]) -> bool {
+        match self {
+            Self::Atom(i) => assignment[*i],
+            Self::Not(a) => !a.eval(assignment),
+            Self::Imp(a, b) => !a.eval(assignment) || b.eval(assignment),
+        }
+    }
+}
+
+/// A Hilbert-style proof whose formulas are only found by unification once pushed,
+/// possibly from assumptions still to be discharged
+struct Pf {
+    kind: PfKind,
+    /// Bit set of the assumptions it uses
+    assumptions: u64,
+    /// Number of pushes it takes, saturating
+    size: usize,
+}
+
+enum PfKind {
+    Push(Step),
+    Assume(usize),
+    /// Modus ponens from the minor premise and the implication
+    Mp(Rc<Pf>, Rc<Pf>),
+}
+
+fn assume(i: usize) -> Rc<Pf> {
+    Rc::new(Pf {
+        kind: PfKind::Assume(i),
+        assumptions: 1 << i,
+        size: 1,
+    })
+}
+
+fn leaf(step: Step) -> Rc<Pf> {
+    Rc::new(Pf {
+        kind: PfKind::Push(step),
+        assumptions: 0,
+        size: 1,
+    })
+}
+
+fn mp(minor: Rc<Pf>, major: Rc<Pf>) -> Rc<Pf> {
+    Rc::new(Pf {
+        assumptions: minor.assumptions | major.assumptions,
+        size: minor.size.saturating_add(major.size).saturating_add(1),
+        kind: PfKind::Mp(minor, major),
+    })
+}
+
+/// The propositional axioms and the deduction theorem
+struct Hilbert {
+    ax_mp: Step,
+    ax1: Step,
+    ax2: Step,
+    ax3: Step,
+    /// `|- ( A -> A )`
+    id: Rc<Pf>,
+}
+
+impl Hilbert {
+    fn new(ax_mp: Step, ax1: Step, ax2: Step, ax3: Step) -> Self {
+        let id = mp(leaf(ax1), mp(leaf(ax1), leaf(ax2)));
+        Self {
+            ax_mp,
+            ax1,
+            ax2,
+            ax3,
+            id,
+        }
+    }
+
+    /// Turn a proof of `B` from assumption `i`, say `A`, into a proof of `( A -> B )`
+    fn deduce(&self, pf: &Rc<Pf>, i: usize) -> Rc<Pf> {
+        self.deduce_shared(pf, i, &mut HashMap::new())
+    }
+
+    /// `done` holds the subproofs already turned, as lemmas are shared between proofs
+    fn deduce_shared(
+        &self,
+        pf: &Rc<Pf>,
+        i: usize,
+        done: &mut HashMap<*const Pf, Rc<Pf>>,
+    ) -> Rc<Pf> {
+        if pf.assumptions & (1 << i) == 0 {
+            return mp(pf.clone(), leaf(self.ax1));
+        }
+        if let Some(deduced) = done.get(&Rc::as_ptr(pf)) {
+            return deduced.clone();
+        }
+        let deduced = match &pf.kind {
+            PfKind::Mp(minor, major) => {
+                let minor = self.deduce_shared(minor, i, done);
+                let major = self.deduce_shared(major, i, done);
+                mp(minor, mp(major, leaf(self.ax2)))
+            }
+            // Only the assumption itself uses it
+            PfKind::Push(_) | PfKind::Assume(_) => self.id.clone(),
+        };
+        done.insert(Rc::as_ptr(pf), deduced.clone());
+        deduced
+    }
+
+    fn push_actions(&self, pf: &Pf, out: &mut Vec<Action>) {
+        match &pf.kind {
+            PfKind::Push(step) => out.push(Action::Push(*step)),
+            PfKind::Assume(_) => unreachable!("every assumption is discharged"),
+            PfKind::Mp(minor, major) => {
+                self.push_actions(minor, out);
+                self.push_actions(major, out);
+                out.push(Action::Push(self.ax_mp));
+            }
+        }
+    }
+}
+
+/// Kalmár's proof of completeness: for every assignment of the atoms,
+/// the formula or its negation follows from the atoms or their negations,
+/// and the cases of each atom are then merged
+struct Kalmar {
+    hilbert: Hilbert,
+    /// `|- ( -. A -> ( A -> B ) )`
+    pm2_21: Rc<Pf>,
+    /// `|- ( A -> -. -. A )`
+    notnot1: Rc<Pf>,
+    /// `|- ( A -> ( -. B -> -. ( A -> B ) ) )`
+    mt: Rc<Pf>,
+    /// `|- ( ( A -> B ) -> ( ( -. A -> B ) -> B ) )`
+    cases: Rc<Pf>,
+}
+
+impl Kalmar {
+    fn new(hilbert: Hilbert) -> Self {
+        let h = &hilbert;
+        let (a0, a1, a2) = (assume(0), assume(1), assume(2));
+        let pm2_21 = h.deduce(&mp(mp(a0.clone(), leaf(h.ax1)), leaf(h.ax3)), 0);
+        // `|- ( -. -. A -> A )`
+        let notnotr = h.deduce(
+            &mp(a0.clone(), mp(mp(a0.clone(), pm2_21.clone()), leaf(h.ax3))),
+            0,
+        );
+        let notnot1 = mp(notnotr.clone(), leaf(h.ax3));
+        // `|- ( ( A -> B ) -> ( -. B -> -. A ) )`
+        let con3 = {
+            let notnot_b = mp(mp(mp(a1.clone(), notnotr), a0.clone()), notnot1.clone());
+            h.deduce(&mp(h.deduce(&notnot_b, 1), leaf(h.ax3)), 0)
+        };
+        let mt = h.deduce(
+            &mp(h.deduce(&mp(a0.clone(), a1.clone()), 1), con3.clone()),
+            0,
+        );
+        // `|- ( ( -. A -> A ) -> A )`
+        let pm2_18 = {
+            let any = mp(mp(a1.clone(), a0.clone()), mp(a1.clone(), pm2_21.clone()));
+            h.deduce(&mp(a0.clone(), mp(h.deduce(&any, 1), leaf(h.ax3))), 0)
+        };
+        let cases = {
+            let b = mp(mp(a2, mp(a0, con3)), a1);
+            h.deduce(&h.deduce(&mp(h.deduce(&b, 2), pm2_18), 1), 0)
+        };
+        Self {
+            hilbert,
+            pm2_21,
+            notnot1,
+            mt,
+            cases,
+        }
+    }
+
+    /// Proof of the formula if it is true under the assignment, of its negation otherwise,
+    /// from the assumption for each atom of it or its negation following the assignment
+    fn row(&self, formula: &Skeleton, assignment: &[bool]) -> Rc<Pf> {
+        match formula {
+            Skeleton::Atom(i) => assume(*i),
+            Skeleton::Not(a) => {
+                let pa = self.row(a, assignment);
+                if a.eval(assignment) {
+                    mp(pa, self.notnot1.clone())
+                } else {
+                    pa
+                }
+            }
+            Skeleton::Imp(a, b) => {
+                if !a.eval(assignment) {
+                    mp(self.row(a, assignment), self.pm2_21.clone())
+                } else if b.eval(assignment) {
+                    mp(self.row(b, assignment), leaf(self.hilbert.ax1))
+                } else {
+                    let pa = self.row(a, assignment);
+                    mp(self.row(b, assignment), mp(pa, self.mt.clone()))
+                }
+            }
+        }
+    }
+
+    /// Proof of a tautology from the assumptions for the atoms assigned so far,
+    /// unless it takes more than `max_steps` pushes
+    fn prove(
+        &self,
+        formula: &Skeleton,
+        num_atoms: usize,
+        assignment: &mut Vec<bool>,
+        max_steps: usize,
+    ) -> Option<Rc<Pf>> {
+        let k = assignment.len();
+        let pf = if k == num_atoms {
+            self.row(formula, assignment)
+        } else {
+            assignment.push(true);
+            let pos = self.prove(formula, num_atoms, assignment, max_steps);
+            assignment.pop();
+            assignment.push(false);
+            let neg = self.prove(formula, num_atoms, assignment, max_steps);
+            assignment.pop();
+            let pos = self.hilbert.deduce(&pos?, k);
+            let neg = self.hilbert.deduce(&neg?, k);
+            mp(neg, mp(pos, self.cases.clone()))
+        };
+        (pf.size <= max_steps).then_some(pf)
+    }
+}
+
+/// First application of the syntax axiom in a tree, if its arguments are variables
+fn find_app(tree: &Tree, label: Label) -> Option<&[Tree]> {
+    match tree {
+        Tree::App(l, args) if *l == label => Some(args),
+        Tree::App(_, args) => args.iter().find_map(|arg| find_app(arg, label)),
+        _ => None,
+    }
+}
+
+/// Atoms of the formulas of a proof, with the instances of the definitions relating them
+struct Atoms<'a> {
+    ctx: &'a Context,
+    /// Definitions must come before this statement
+    before: StatementAddress,
+    atoms: Vec<Tree>,
+    premises: Vec<(Step, Tree)>,
+}
+
+impl Atoms<'_> {
+    fn skeleton(&mut self, tree: &Tree) -> Option<Skeleton> {
+        if let Tree::App(label, args) = tree {
+            match self.ctx.syntax_label(*label) {
+                b"wn" => return Some(Skeleton::Not(Box::new(self.skeleton(&args[0])?))),
+                b"wi" => {
+                    let a = self.skeleton(&args[0])?;
+                    let b = self.skeleton(&args[1])?;
+                    return Some(Skeleton::Imp(Box::new(a), Box::new(b)));
+                }
+                _ => {}
+            }
+        }
+        if let Some(i) = self.atoms.iter().position(|atom| atom == tree) {
+            return Some(Skeleton::Atom(i));
+        }
+        // Assumptions are kept in a bit set
+        if self.atoms.len() == u64::BITS as usize {
+            return None;
+        }
+        self.atoms.push(tree.clone());
+        if let Some(premise) = self.definition(tree) {
+            self.premises.push(premise);
+        }
+        Some(Skeleton::Atom(self.atoms.len() - 1))
+    }
+
+    /// Instance of the definition of the connective at the root of the tree
+    fn definition(&self, tree: &Tree) -> Option<(Step, Tree)> {
+        let ctx = self.ctx;
+        let Tree::App(label, args) = tree else {
+            return None;
+        };
+        let syntax = ctx.syntax_label(*label);
+        let (_, def_label) = DEFINITIONS.iter().find(|(s, _)| *s == syntax)?;
+        let def = ctx.metamath_db.statement(def_label)?.address();
+        if !ctx.precedes(def, self.before) {
+            return None;
+        }
+        let conclusion = &ctx.rule(def)?.conclusion.tree;
+        let values = find_app(conclusion, *label)?
+            .iter()
+            .zip(args)
+            .filter_map(|(var, arg)| match var {
+                Tree::Var(var) => Some((*var, arg.clone())),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        let instance =
+            conclusion.map_vars(&mut |var| values.get(&var).cloned().unwrap_or(Tree::Var(var)));
+        Some((Step::Stmt(def), instance))
+    }
+}
+
+impl State {
+    /// Actions pushing a proof of the level goal if it follows from the hypotheses
+    /// by propositional logic, taking at most `max_steps` pushes.
+    /// The proof only uses the propositional axioms and the definitions of the connectives,
+    /// and is always found for tautologies when the budget is large enough.
+    pub fn prove_prop(
+        &self,
+        ctx: &Context,
+        lemmas: &[Lemma],
+        max_steps: usize,
+    ) -> Option<Vec<Action>> {
+        let db = &ctx.metamath_db;
+        let provable_typecode = db.grammar_result().provable_typecode();
+        let before = self.current_level_stmt_addr;
+        let axioms = AXIOMS
+            .iter()
+            .map(|label| {
+                let addr = db.statement(label)?.address();
+                ctx.precedes(addr, before).then_some(Step::Stmt(addr))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let &[ax_mp, ax1, ax2, ax3] = axioms.as_slice() else {
+            return None;
+        };
+        let goal = self.goal(ctx);
+        let hyps = self.level_hyps(ctx);
+        let mut atoms = Atoms {
+            ctx,
+            before,
+            atoms: Vec::new(),
+            premises: Vec::new(),
+        };
+        if goal.typecode != provable_typecode {
+            return None;
+        }
+        let goal_skeleton = atoms.skeleton(&goal.tree)?;
+        let hyp_skeletons = hyps
+            .iter()
+            .map(|&addr| {
+                let hyp = ctx.stmt_expr(addr)?;
+                if hyp.typecode != provable_typecode {
+                    return None;
+                }
+                atoms.skeleton(&hyp.tree)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        // Definitions relate the atoms of defined connectives, and may bring more atoms
+        let mut def_skeletons = Vec::new();
+        while def_skeletons.len() < atoms.premises.len() {
+            let tree = atoms.premises[def_skeletons.len()].1.clone();
+            def_skeletons.push(atoms.skeleton(&tree)?);
+        }
+        // Every premise is assumed by the formula to prove
+        let formula = def_skeletons
+            .into_iter()
+            .chain(hyp_skeletons)
+            .rev()
+            .fold(goal_skeleton, |formula, premise| {
+                Skeleton::Imp(Box::new(premise), Box::new(formula))
+            });
+        let premises = atoms
+            .premises
+            .iter()
+            .map(|(step, _)| *step)
+            .chain(hyps.into_iter().map(Step::Stmt));
+        let num_atoms = atoms.atoms.len();
+        // Each assignment of the atoms takes at least a push
+        if num_atoms >= usize::BITS as usize || 1 << num_atoms > max_steps {
+            return None;
+        }
+        let is_tautology = (0..1usize << num_atoms).all(|bits| {
+            let assignment = (0..num_atoms)
+                .map(|i| bits & (1 << i) != 0)
+                .collect::<Vec<_>>();
+            formula.eval(&assignment)
+        });
+        if !is_tautology {
+            return None;
+        }
+        let kalmar = Kalmar::new(Hilbert::new(ax_mp, ax1, ax2, ax3));
+        let mut pf = kalmar.prove(&formula, num_atoms, &mut Vec::new(), max_steps)?;
+        for premise in premises {
+            pf = mp(leaf(premise), pf);
+        }
+        if pf.size > max_steps {
+            return None;
+        }
+        let mut actions = Vec::new();
+        kalmar.hilbert.push_actions(&pf, &mut actions);
+        // The formulas of the proof are only found by unification, so check them by replaying
+        let finished = self
+            .apply_all(ctx, lemmas, &actions)
+            .is_some_and(|state| state.level_finished(ctx));
+        finished.then_some(actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expr::tests::context;
+
+    #[test]
+    fn proves_tautologies_within_the_budget() {
+        let ctx = context();
+        for level in ["a1", "notnotr", "syl"] {
+            let state = ctx.initial_state(Some(level));
+            let actions = state.prove_prop(&ctx, &[], 100_000).unwrap();
+            let proven = state.apply_all(&ctx, &[], &actions).unwrap();
+            assert!(proven.level_finished(&ctx));
+            // The budget cuts off the proof instead of bounding a search
+            assert!(state.prove_prop(&ctx, &[], actions.len() - 1).is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_non_tautologies() {
+        let ctx = context();
+        let state = ctx.initial_state(Some("a1c"));
+        assert!(state.prove_prop(&ctx, &[], 100_000).is_none());
+    }
+}
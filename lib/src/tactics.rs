@@ -0,0 +1,139 @@
+use itertools::Itertools;
+
+use crate::{library::may_match, Action, Context, Expr, Lemma, State, Step};
+
+/// Bound on the entries added by a single run of `Tactic::MpSaturate`
+const MAX_SATURATE_ENTRIES: usize = 32;
+
+/// Automation for steps players find boring
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tactic {
+    /// Push a hypothesis of the level that already equals the goal
+    CloseWithHyp,
+    /// Repeatedly apply statements with two essential hypotheses to pairs of stack entries
+    MpSaturate,
+    /// Apply each dependency that consumes the top entry
+    TryEveryDep,
+}
+
+impl Tactic {
+    pub const ALL: [Self; 3] = [Self::CloseWithHyp, Self::MpSaturate, Self::TryEveryDep];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CloseWithHyp => "Close with hypothesis",
+            Self::MpSaturate => "MP-saturate",
+            Self::TryEveryDep => "Try every dependency",
+        }
+    }
+}
+
+impl State {
+    /// Apply actions in order, failing if any of them fails
+    pub fn apply_all(&self, ctx: &Context, lemmas: &[Lemma], actions: &[Action]) -> Option<Self> {
+        actions.iter().try_fold(self.clone(), |state, &action| {
+            state.apply(ctx, lemmas, action)
+        })
+    }
+
+    /// Candidate action sequences found by a tactic, for the player to preview and pick from
+    pub fn tactic(&self, ctx: &Context, lemmas: &[Lemma], tactic: Tactic) -> Vec<Vec<Action>> {
+        match tactic {
+            Tactic::CloseWithHyp => self.close_with_hyp(ctx, lemmas).into_iter().collect(),
+            Tactic::MpSaturate => Some(self.mp_saturate(ctx, lemmas))
+                .filter(|actions| !actions.is_empty())
+                .into_iter()
+                .collect(),
+            Tactic::TryEveryDep => self.try_every_dep(ctx, lemmas),
+        }
+    }
+
+    fn close_with_hyp(&self, ctx: &Context, lemmas: &[Lemma]) -> Option<Vec<Action>> {
+        self.level_hyps(ctx)
+            .into_iter()
+            .map(|addr| vec![Action::Push(Step::Stmt(addr))])
+            .find(|actions| {
+                self.apply_all(ctx, lemmas, actions)
+                    .is_some_and(|state| state.level_finished(ctx))
+            })
+    }
+
+    /// Statements and lemmas with two essential hypotheses which may take `first` and `second`
+    fn mp_steps(&self, ctx: &Context, lemmas: &[Lemma], first: &Expr, second: &Expr) -> Vec<Step> {
+        self.consumers(ctx, lemmas, second)
+            .into_iter()
+            .filter(|&step| {
+                ctx.step_rule(step, lemmas).is_some_and(|rule| {
+                    let mut essential = rule.hyps.iter().filter(|(essential, _)| *essential);
+                    match (essential.next(), essential.next(), essential.next()) {
+                        (Some((_, hyp)), Some(_), None) => {
+                            hyp.typecode == first.typecode && may_match(&hyp.tree, &first.tree)
+                        }
+                        _ => false,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn mp_saturate(&self, ctx: &Context, lemmas: &[Lemma]) -> Vec<Action> {
+        let mut state = self.clone();
+        let mut actions = Vec::new();
+        let mut added = 0;
+        'saturate: while added < MAX_SATURATE_ENTRIES {
+            let len = state.proof_stack.len();
+            for (i, j) in (0..len).cartesian_product(0..len) {
+                if i == j {
+                    continue;
+                }
+                let steps = state.mp_steps(
+                    ctx,
+                    lemmas,
+                    &state.proof_stack[i].expr,
+                    &state.proof_stack[j].expr,
+                );
+                for step in steps {
+                    // Copy both entries to the top, keeping the originals
+                    let candidate = [
+                        Action::Copy(i),
+                        Action::Move(i + 1, len),
+                        Action::Copy(j),
+                        Action::Move(j + 1, len + 1),
+                        Action::Push(step),
+                    ];
+                    let Some(next_state) = state.apply_all(ctx, lemmas, &candidate) else {
+                        continue;
+                    };
+                    let (new, old) = next_state.proof_stack.split_last().unwrap();
+                    if old.len() == len
+                        && !new.expr.has_work_vars()
+                        && old.iter().all(|entry| entry.expr != new.expr)
+                    {
+                        actions.extend(candidate);
+                        state = next_state;
+                        added += 1;
+                        continue 'saturate;
+                    }
+                }
+            }
+            break;
+        }
+        actions
+    }
+
+    fn try_every_dep(&self, ctx: &Context, lemmas: &[Lemma]) -> Vec<Vec<Action>> {
+        let Some(top) = self.proof_stack.last() else {
+            return Vec::new();
+        };
+        let len = self.proof_stack.len();
+        self.consumers(ctx, lemmas, &top.expr)
+            .into_iter()
+            .filter(|&step| {
+                // Pushes which don't consume the top entry are not interesting
+                self.push(ctx, lemmas, step)
+                    .is_some_and(|next_state| next_state.proof_stack.len() <= len)
+            })
+            .map(|step| vec![Action::Push(step)])
+            .collect()
+    }
+}
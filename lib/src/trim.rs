@@ -0,0 +1,366 @@
+use std::{collections::HashSet, fmt};
+
+use metamath_rs::{formula::Label, statement::StatementAddress, StatementType};
+
+use crate::{Context, Tree};
+
+/// Column after which `write_tokens` wraps lines
+const LINE_WIDTH: usize = 79;
+
+/// Typesetting commands defining how to render a math symbol
+const TYPESETTING_DEFS: [&[u8]; 3] = [b"latexdef", b"htmldef", b"althtmldef"];
+
+/// Why a level spec names no levels
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LevelSpecError {
+    pub spec: String,
+    pub message: &'static str,
+}
+
+impl fmt::Display for LevelSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.message, self.spec)
+    }
+}
+
+/// A `;` terminated command of a `$t` or `$j` comment
+struct Command {
+    /// Position in the comment, including the `;`
+    start: usize,
+    end: usize,
+    /// Unquoted tokens
+    tokens: Vec<Vec<u8>>,
+}
+
+/// Commands of the text following `$t` or `$j`, dropping `/* */` comments between them
+fn parse_commands(text: &[u8]) -> Vec<Command> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    let mut start = None;
+    let mut tokens = Vec::new();
+    while i < text.len() {
+        match text[i] {
+            b if b.is_ascii_whitespace() => i += 1,
+            b'/' if text[i..].starts_with(b"/*") => {
+                i = text[i..]
+                    .windows(2)
+                    .position(|w| w == b"*/")
+                    .map_or(text.len(), |end| i + end + 2);
+            }
+            b';' => {
+                out.push(Command {
+                    start: start.unwrap_or(i),
+                    end: i + 1,
+                    tokens: std::mem::take(&mut tokens),
+                });
+                start = None;
+                i += 1;
+            }
+            quote @ (b'\'' | b'"') => {
+                start.get_or_insert(i);
+                // A doubled quote stands for the quote itself
+                let mut token = Vec::new();
+                i += 1;
+                while i < text.len() {
+                    if text[i] == quote {
+                        if text.get(i + 1) != Some(&quote) {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    token.push(text[i]);
+                    i += 1;
+                }
+                tokens.push(token);
+                i += 1;
+            }
+            _ => {
+                start.get_or_insert(i);
+                let len = text[i..]
+                    .iter()
+                    .position(|&b| b.is_ascii_whitespace() || b == b';')
+                    .unwrap_or(text.len() - i);
+                tokens.push(text[i..i + len].to_vec());
+                i += len;
+            }
+        }
+    }
+    out
+}
+
+/// `$( $t ... $)` or `$( $j ... $)` comment with only the commands `keep` accepts
+fn write_commands(out: &mut Vec<u8>, contents: &[u8], keep: impl Fn(&[Vec<u8>]) -> bool) {
+    let Some(keyword) = contents.windows(2).position(|w| w == b"$t" || w == b"$j") else {
+        return;
+    };
+    let (head, body) = contents.split_at(keyword + 2);
+    out.extend_from_slice(b"$(");
+    out.extend_from_slice(head);
+    out.push(b'\n');
+    for command in parse_commands(body) {
+        if keep(&command.tokens) {
+            out.extend_from_slice(b"  ");
+            out.extend_from_slice(&body[command.start..command.end]);
+            out.push(b'\n');
+        }
+    }
+    out.extend_from_slice(b"$)\n");
+}
+
+/// Tokens separated by spaces, wrapping long lines
+fn write_tokens<'a>(out: &mut Vec<u8>, tokens: impl IntoIterator<Item = &'a [u8]>) {
+    let mut column = out.len() - out.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    for token in tokens {
+        if column + 1 + token.len() > LINE_WIDTH {
+            out.extend_from_slice(b"\n   ");
+            column = 3;
+        }
+        out.push(b' ');
+        out.extend_from_slice(token);
+        column += 1 + token.len();
+    }
+}
+
+/// Visit the label of every syntax axiom and variable of a tree
+fn for_each_label(tree: &Tree, f: &mut impl FnMut(Label)) {
+    match tree {
+        Tree::Var(label) => f(*label),
+        Tree::Work(_, _) => {}
+        Tree::App(label, args) => {
+            f(*label);
+            args.iter().for_each(|arg| for_each_label(arg, f));
+        }
+    }
+}
+
+impl Context {
+    /// Levels from `first` to `last`, both included, which the level filter keeps,
+    /// or an error if `last` comes before `first`
+    pub fn levels_between(
+        &self,
+        first: StatementAddress,
+        last: StatementAddress,
+    ) -> Result<Vec<StatementAddress>, LevelSpecError> {
+        let mut levels = Vec::new();
+        for stmt in self.metamath_db.statements_range_address(first..) {
+            if self.is_level(&stmt) {
+                levels.push(stmt.address());
+            }
+            if stmt.address() == last {
+                return Ok(levels);
+            }
+        }
+        Err(LevelSpecError {
+            spec: format!("{}..{}", self.label(first), self.label(last)),
+            message: "the last level comes before the first in",
+        })
+    }
+
+    /// Levels given by a label, or by `first..last` labels for `levels_between`
+    pub fn parse_levels(&self, spec: &str) -> Result<Vec<StatementAddress>, LevelSpecError> {
+        let addr = |label: &str| {
+            self.statement_addr(label).ok_or_else(|| LevelSpecError {
+                spec: label.into(),
+                message: "unknown statement",
+            })
+        };
+        match spec.split_once("..") {
+            Some((first, last)) => self.levels_between(addr(first)?, addr(last)?),
+            None => Ok(vec![addr(spec)?]),
+        }
+    }
+
+    /// Statements needed to state and check `levels`: everything their proofs use,
+    /// transitively, with the hypotheses and syntax axioms of each
+    fn trim_closure(&self, levels: &[StatementAddress]) -> HashSet<StatementAddress> {
+        let db = &self.metamath_db;
+        let mut kept = HashSet::new();
+        let mut todo = levels.to_vec();
+        while let Some(addr) = todo.pop() {
+            if !kept.insert(addr) {
+                continue;
+            }
+            let stmt_type = db.statement_by_address(addr).statement_type();
+            if stmt_type == StatementType::Provable {
+                todo.extend(self.deps(addr));
+            }
+            todo.extend(self.hyp_addrs(addr));
+            if let Some(expr) = self.stmt_expr(addr) {
+                for_each_label(&expr.tree, &mut |label| {
+                    todo.extend(db.statement_by_label(label).map(|stmt| stmt.address()))
+                });
+            }
+        }
+        kept
+    }
+
+    /// Database source with only the statements `levels` need, the declarations
+    /// and `$d`s of the symbols these use, and the typesetting definitions of those symbols
+    pub fn trim(&self, levels: &[StatementAddress]) -> Vec<u8> {
+        let db = &self.metamath_db;
+        let names = db.name_result();
+        let kept = self.trim_closure(levels);
+        let symbols = kept
+            .iter()
+            .flat_map(|&addr| db.statement_by_address(addr).math_iter())
+            .map(|tok| tok.slice)
+            .collect::<HashSet<_>>();
+        // Whether a `$j` token names a statement or symbol that was dropped
+        let dropped = |token: &[u8]| {
+            db.statement(token)
+                .is_some_and(|stmt| !kept.contains(&stmt.address()))
+                || names.lookup_symbol(token).is_some() && !symbols.contains(token)
+        };
+        let mut out = Vec::new();
+        // Start of the output of each open `${`, to drop groups left empty
+        let mut groups = Vec::new();
+        for stmt in db.statements() {
+            let stmt_type = stmt.statement_type();
+            let keyword: &[u8] = match stmt_type {
+                StatementType::TypesettingComment => {
+                    let (buf, span) = stmt.comment_contents();
+                    write_commands(&mut out, span.as_ref(buf), |tokens| match tokens {
+                        [keyword, symbol, ..] if TYPESETTING_DEFS.contains(&&keyword[..]) => {
+                            symbols.contains(&symbol[..])
+                        }
+                        _ => true,
+                    });
+                    continue;
+                }
+                StatementType::AdditionalInfoComment => {
+                    let (buf, span) = stmt.comment_contents();
+                    write_commands(&mut out, span.as_ref(buf), |tokens| {
+                        !tokens.iter().skip(1).any(|token| dropped(token))
+                    });
+                    continue;
+                }
+                StatementType::OpenGroup => {
+                    groups.push(out.len());
+                    out.extend_from_slice(b"${\n");
+                    continue;
+                }
+                StatementType::CloseGroup => {
+                    match groups.pop() {
+                        Some(start) if out.len() == start + 3 => out.truncate(start),
+                        _ => out.extend_from_slice(b"$}\n"),
+                    }
+                    continue;
+                }
+                StatementType::Constant => b"$c",
+                StatementType::Variable => b"$v",
+                StatementType::Disjoint => b"$d",
+                StatementType::Floating => b"$f",
+                StatementType::Essential => b"$e",
+                StatementType::Axiom => b"$a",
+                StatementType::Provable => b"$p",
+                _ => continue,
+            };
+            let mut math = stmt.math_iter().map(|tok| tok.slice).collect::<Vec<_>>();
+            let is_kept = match stmt_type {
+                StatementType::Constant | StatementType::Variable => {
+                    math.retain(|tok| symbols.contains(tok));
+                    !math.is_empty()
+                }
+                StatementType::Disjoint => {
+                    math.retain(|tok| symbols.contains(tok));
+                    math.len() >= 2
+                }
+                _ => kept.contains(&stmt.address()),
+            };
+            if !is_kept {
+                continue;
+            }
+            if !stmt.label().is_empty() {
+                out.extend_from_slice(stmt.label());
+                out.push(b' ');
+            }
+            out.extend_from_slice(keyword);
+            write_tokens(&mut out, math);
+            if stmt_type == StatementType::Provable {
+                write_tokens(&mut out, [&b"$="[..]]);
+                write_tokens(
+                    &mut out,
+                    (0..stmt.proof_len()).map(|i| stmt.proof_slice_at(i)),
+                );
+            }
+            write_tokens(&mut out, [&b"$."[..]]);
+            out.push(b'\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_commands, write_commands};
+    use crate::{Context, TypesetMode};
+
+    /// Enough for a proven level, with statements it doesn't need
+    const DB: &str = "
+        $( $j syntax 'wff'; /* provable */ syntax '|-' as 'wff'; $)
+        $c ( ) -> -. wff |- $.
+        $v ph ps $.
+        $( $t htmldef \"ph\" as \"&phi;\"; htmldef \"-.\" as \"&not;\"; $)
+        wph $f wff ph $.
+        wps $f wff ps $.
+        wn $a wff -. ph $.
+        wi $a wff ( ph -> ps ) $.
+        ax-1 $a |- ( ph -> ( ps -> ph ) ) $.
+        ax-3 $a |- ( ( -. ph -> -. ps ) -> ( ps -> ph ) ) $.
+        ${
+            min $e |- ph $.
+            maj $e |- ( ph -> ps ) $.
+            ax-mp $a |- ps $.
+        $}
+        ${
+            a1i.1 $e |- ph $.
+            a1i $p |- ( ps -> ph ) $= wph wps wph wi a1i.1 wph wps ax-1 ax-mp $.
+        $}
+    ";
+
+    fn context(data: impl Into<Vec<u8>>) -> Context {
+        Context::load("test.mm", data, TypesetMode::AltHtml)
+    }
+
+    #[test]
+    fn trimmed_database_keeps_levels() {
+        let ctx = context(DB);
+        let levels = ctx.parse_levels("a1i").unwrap();
+        let trimmed = context(ctx.trim(&levels));
+        let a1i = trimmed.statement_addr("a1i").unwrap();
+        let stmt = trimmed.metamath_db.statement_by_address(a1i);
+        assert!(trimmed.is_level(&stmt));
+        for dep in ctx.deps(levels[0]) {
+            assert!(trimmed.statement_addr(&ctx.label(dep)).is_some());
+        }
+        assert!(trimmed.statement_addr("wi").is_some());
+        for dropped in ["wn", "ax-3"] {
+            assert!(trimmed.statement_addr(dropped).is_none());
+        }
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let body = b" syntax 'wff'; /* provable */ syntax \"|-\" as 'wff';\n";
+        let mut out = Vec::new();
+        write_commands(&mut out, &[&b" $j"[..], &body[..]].concat(), |_| true);
+        let tokens = |text: &[u8]| {
+            parse_commands(text)
+                .into_iter()
+                .map(|command| command.tokens)
+                .collect::<Vec<_>>()
+        };
+        let written = out.strip_prefix(b"$( $j").unwrap().strip_suffix(b"$)\n");
+        assert_eq!(tokens(written.unwrap()), tokens(body));
+        assert_eq!(tokens(body)[1], [&b"syntax"[..], b"|-", b"as", b"wff"]);
+    }
+
+    #[test]
+    fn ranges_must_be_in_order() {
+        let ctx = context(DB);
+        assert_eq!(ctx.parse_levels("ax-1..a1i").unwrap().len(), 1);
+        let error = ctx.parse_levels("a1i..ax-1").unwrap_err();
+        assert_eq!(error.spec, "a1i..ax-1");
+        assert!(ctx.parse_levels("a1i..nothing").is_err());
+    }
+}
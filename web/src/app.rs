@@ -1,6 +1,10 @@
-use lib::{Context, StatementAddress};
+use lib::{
+    Action, ButtonCache, CommentPart, Context, GoalTree, Lemma, LevelOrder, Library, Macro,
+    Matched, NodeStatus, Progress, SearchResult, State, Step, StepCache, Tactic, TruthTable,
+};
 use std::fmt::Write;
 use std::sync::LazyLock;
+use web_sys::HtmlInputElement;
 use yew::prelude::*;
 use yew_hooks::use_local_storage;
 
@@ -8,7 +12,7 @@ static CTX: LazyLock<Context> = LazyLock::new(|| {
     Context::load(
         "set.mm",
         include_bytes!("/tmp/dump/set.mm"),
-        lib::TypesetMode::AltHtml,
+        lib::TypesetMode::MathML,
     )
 });
 
@@ -21,8 +25,40 @@ fn string_to_html(s: String) -> Html {
     Html::from_html_unchecked(AttrValue::from(s))
 }
 
-fn render_inference(stmt_addr: StatementAddress) -> String {
-    let (hyps, conclusion) = CTX.render_inference(stmt_addr);
+/// Description of a statement, whose cross-references to levels call `start_level`
+fn description_html(parts: Vec<CommentPart>, start_level: &Callback<String>) -> Html {
+    parts
+        .into_iter()
+        .map(|part| match part {
+            CommentPart::Text(text) => html! { { text } },
+            CommentPart::ParagraphBreak => html! { <><br/><br/></> },
+            CommentPart::Italic(text) => html! { <i> { text } </i> },
+            CommentPart::Math { rendered, .. } => string_to_html(rendered),
+            CommentPart::Label {
+                label,
+                is_level: true,
+            } => {
+                let onclick = {
+                    let label = label.clone();
+                    start_level.reform(move |e: MouseEvent| {
+                        e.prevent_default();
+                        label.clone()
+                    })
+                };
+                html! { <a href="#" {onclick}> { label } </a> }
+            }
+            CommentPart::Label { label, .. } => html! { <code> { label } </code> },
+            CommentPart::Url(url) => html! { <a href={url.clone()}> { url } </a> },
+            CommentPart::Citation(tag) => html! { { format!("[{tag}]") } },
+        })
+        .collect()
+}
+
+/// Button text and hover title of each step
+type ButtonTexts = StepCache<(String, String)>;
+
+fn render_inference(lemmas: &[Lemma], step: Step) -> String {
+    let (hyps, conclusion) = CTX.render_step_inference(step, lemmas);
     let hyps = hyps.into_iter().fold(String::new(), |mut out, hyp| {
         write!(out, "{hyp} <br/>").unwrap();
         out
@@ -35,22 +71,280 @@ fn render_inference(stmt_addr: StatementAddress) -> String {
     format!("<div style='display: inline-block'> {inference} </div>")
 }
 
+/// A button per step, each enabled or not, calling `set` with the step clicked
+fn dep_buttons(
+    lemmas: &[Lemma],
+    texts: &mut ButtonTexts,
+    buttons: impl IntoIterator<Item = (Step, bool)>,
+    level_finished: bool,
+    set: impl Fn(Step) + Clone + 'static,
+) -> Html {
+    buttons
+        .into_iter()
+        .map(|(step, enabled)| {
+            let (text, title) = texts.get(step, || {
+                let inference = render_inference(lemmas, step);
+                let text = format!("{} <br/> <br/> {inference}", CTX.step_label(step, lemmas));
+                let title = match step {
+                    Step::Stmt(addr) => CommentPart::plain_text(&CTX.description(addr)),
+                    Step::Lemma(_) => String::new(),
+                };
+                (text, title)
+            });
+            let text = string_to_html(text.clone());
+            let title = title.clone();
+            let (disabled, onclick) = if enabled {
+                let set = set.clone();
+                (level_finished, Some(Callback::from(move |_| set(step))))
+            } else {
+                (true, None)
+            };
+            html! {
+                <button class="dep-btn" {title} {disabled} {onclick}>
+                    { text }
+                </button>
+            }
+        })
+        .collect::<Html>()
+}
+
+fn truth_table_html(table: Option<TruthTable>) -> Html {
+    // Larger tables are summarized only
+    const MAX_ROWS: usize = 64;
+    let Some(table) = table else {
+        return html! { <p> { "Not purely propositional" } </p> };
+    };
+    let value_name = |value: bool| if value { "T" } else { "F" };
+    let summary = match table.falsifying_assignment() {
+        None => String::from("Tautology"),
+        Some(assignment) => {
+            let assignment = table
+                .vars
+                .iter()
+                .zip(assignment)
+                .map(|(var, &value)| format!("{var} = {}", value_name(value)))
+                .collect::<Vec<_>>();
+            format!("Not a tautology, false when {}", assignment.join(", "))
+        }
+    };
+    let rows = (table.rows.len() <= MAX_ROWS).then(|| {
+        let header = table
+            .vars
+            .iter()
+            .map(|var| html! { <th> { var } </th> })
+            .collect::<Html>();
+        let rows = table
+            .rows
+            .iter()
+            .map(|(assignment, value)| {
+                let cells = assignment
+                    .iter()
+                    .map(|&var_value| html! { <td> { value_name(var_value) } </td> })
+                    .collect::<Html>();
+                html! {
+                    <tr>
+                        { cells }
+                        <td> <b> { value_name(*value) } </b> </td>
+                    </tr>
+                }
+            })
+            .collect::<Html>();
+        html! {
+            <table>
+                <tr> { header } <th/> </tr>
+                { rows }
+            </table>
+        }
+    });
+    html! {
+        <>
+            <p> { summary } </p>
+            { rows }
+        </>
+    }
+}
+
+/// Callback applying actions, recording them into the macro being recorded if any
+fn actions_callback(
+    state: &UseStateHandle<State>,
+    recording: &UseStateHandle<Option<Macro>>,
+    lemmas: &[Lemma],
+    actions: &[Action],
+) -> Option<Callback<MouseEvent>> {
+    let mut next_state = (**state).clone();
+    let mut next_recording = (**recording).clone();
+    for &action in actions {
+        let after = next_state.apply(&CTX, lemmas, action)?;
+        if let Some(mac) = &mut next_recording {
+            mac.record(&CTX, lemmas, &next_state, action);
+        }
+        next_state = after;
+    }
+    let state = state.clone();
+    let recording = recording.clone();
+    Some(Callback::from(move |_| {
+        state.set(next_state.clone());
+        recording.set(next_recording.clone());
+    }))
+}
+
 #[function_component(App)]
 pub fn app() -> Html {
     let storage = use_local_storage::<String>(String::from("level"));
-    let state = use_state(|| CTX.initial_state(storage.as_deref()));
+    let progress_storage = use_local_storage::<Progress>(String::from("progress"));
+    let progress = (*progress_storage).clone().unwrap_or_default();
+    let state = use_state(|| {
+        CTX.initial_state(storage.as_deref())
+            .with_progress(&CTX, &progress)
+    });
     let current_level_name = CTX.label(state.current_level_stmt_addr);
     if storage.as_deref() != Some(&current_level_name) {
         storage.set(current_level_name.clone());
     }
-    let next_level = state.next_level(&CTX);
+    // Lemmas are only ever added, so their number identifies them
+    let lemmas = {
+        let progress = progress.clone();
+        use_memo(progress.lemmas.len(), move |_| CTX.load_lemmas(&progress))
+    };
+    let lemma_name = use_state(String::new);
+    let recording = use_state(|| None::<Macro>);
+    // Arguments typed for each macro, separated by spaces
+    let macro_args = use_state(Vec::<String>::new);
+    // Candidate action sequences of the last tactic run
+    let tactic_candidates = use_state(Vec::<Vec<Action>>::new);
+    // Stack entry whose truth table is shown
+    let truth_table_entry = use_state(|| None::<usize>);
+    // Most pushes a generated propositional proof may take
+    let prover_budget = use_state(|| 10_000usize);
+    // Goal typed for sandbox mode, and the label before which assertions are available
+    let sandbox_goal_text = use_state(String::new);
+    let sandbox_before = use_state(String::new);
+    let sandbox_error = use_state(|| None::<String>);
+    // Search of open-library mode
+    let library_search = use_state(String::new);
+    let library_unifiable = use_state(|| false);
+    // Assertions found by the last search from the goal or a stack entry
+    let search_results = use_state(Vec::<SearchResult>::new);
+    // Buttons of the stack, recomputed only when the state changes
+    let button_cache = use_mut_ref(ButtonCache::default);
+    // Rendered once per step
+    let button_texts = use_mut_ref(ButtonTexts::default);
+    // Some in goal-directed mode
+    let goal_tree = use_state(|| None::<GoalTree>);
+    // Buttons of goal-directed mode, recomputed only when the tree changes
+    let goal_tree_buttons = {
+        let goal_tree = (*goal_tree).clone();
+        use_memo(goal_tree.as_ref().map(GoalTree::id), move |_| {
+            goal_tree.map(|tree| tree.buttons(&CTX))
+        })
+    };
+    let level_addr = state.current_level_stmt_addr;
+    let level_description = use_memo(level_addr, |&addr| CTX.description(addr));
+    let level_difficulty = use_memo(level_addr, |&addr| CTX.difficulty(addr));
+    let level_inference = use_memo(level_addr, |&addr| render_inference(&[], Step::Stmt(addr)));
+    let locked_deps = {
+        let state = (*state).clone();
+        use_memo(state.id(), move |_| state.locked_deps(&CTX))
+    };
+    // State after each macro, recomputed when the state, lemmas or arguments change
+    let macro_states = {
+        let state = (*state).clone();
+        let lemmas = lemmas.clone();
+        let macros = progress.macros.clone();
+        let args = (*macro_args).clone();
+        use_memo(
+            (state.id(), lemmas.len(), macros.len(), args.clone()),
+            move |_| {
+                macros
+                    .iter()
+                    .enumerate()
+                    .map(|(i, mac)| {
+                        let args = args.get(i).cloned().unwrap_or_else(|| mac.params.join(" "));
+                        let args = args
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect::<Vec<_>>();
+                        state.run_macro(&CTX, &lemmas, mac, &args)
+                    })
+                    .collect::<Vec<_>>()
+            },
+        )
+    };
+    // Lemma each stack entry would make, recomputed when the state, lemmas or name change
+    let entry_lemmas = {
+        let state = (*state).clone();
+        let lemmas = lemmas.clone();
+        let name = (*lemma_name).clone();
+        use_memo((state.id(), lemmas.len(), name.clone()), move |_| {
+            (0..state.proof_stack.len())
+                .map(|i| state.lemma(&CTX, &lemmas, i, &name))
+                .collect::<Vec<_>>()
+        })
+    };
+    // Graph of the level map, rebuilt when the level or the solved levels change
+    let level_map = {
+        let progress = progress.clone();
+        use_memo(
+            (state.current_level_stmt_addr, progress.solved.len()),
+            move |&(level_addr, _)| CTX.dep_graph(&CTX.map_levels(level_addr), &progress),
+        )
+    };
+    // Proof of the finished level, exported once per state
+    let exported_proof = {
+        let state = (*state).clone();
+        let lemmas = lemmas.clone();
+        use_memo((state.id(), lemmas.len()), move |_| {
+            state.export_proof(&CTX, &lemmas)
+        })
+    };
+    let map_zoom = use_state(|| 1.0f64);
+    // Level after the finished one, recomputed when the tree, state or level order change
+    let next_level = {
+        let state = (*state).clone();
+        let goal_tree = (*goal_tree).clone();
+        let key = (
+            goal_tree.as_ref().map(GoalTree::id),
+            state.id(),
+            state.level_order,
+        );
+        *use_memo(key, move |_| match &goal_tree {
+            Some(tree) => tree
+                .next_level(&CTX)
+                .map(|next| next.current_level_stmt_addr),
+            None => state
+                .next_level(&CTX)
+                .map(|next| next.current_level_stmt_addr),
+        })
+    };
     let level_finished = next_level.is_some();
     let next_level_button = {
         let state = state.clone();
+        let goal_tree = goal_tree.clone();
+        let progress_storage = progress_storage.clone();
+        let progress = progress.clone();
+        let current_level_name = current_level_name.clone();
         let (disabled, onclick) = match next_level {
-            Some(next_state) => (
+            Some(next_level_addr) => (
                 false,
-                Some(Callback::from(move |_| state.set(next_state.clone()))),
+                Some(Callback::from(move |_| {
+                    let mut progress = progress.clone();
+                    progress.record_solved(current_level_name.clone());
+                    progress_storage.set(progress.clone());
+                    let next_level_name = CTX.label(next_level_addr);
+                    state.set(
+                        CTX.initial_state(Some(&next_level_name))
+                            .with_library(state.library)
+                            .with_level_order(state.level_order)
+                            .with_progress(&CTX, &progress),
+                    );
+                    if goal_tree.is_some() {
+                        goal_tree.set(Some(
+                            CTX.initial_goal_tree(Some(&next_level_name))
+                                .with_level_order(state.level_order)
+                                .with_progress(&CTX, &progress),
+                        ));
+                    }
+                })),
             ),
             None => (true, None),
         };
@@ -60,27 +354,616 @@ pub fn app() -> Html {
             </button>
         }
     };
-    let deps = state
-        .buttons(&CTX)
-        .into_iter()
-        .map(|(stmt_addr, opt_next_state)| {
-            let state = state.clone();
-            let inference = render_inference(stmt_addr);
-            let text = string_to_html(format!("{} <br/> <br/> {inference}", CTX.label(stmt_addr)));
-            let (disabled, onclick) = match opt_next_state {
-                Some(next_state) => (
-                    level_finished,
-                    Some(Callback::from(move |_| state.set(next_state.clone()))),
+    let mode_checkbox = {
+        let state = state.clone();
+        let progress = progress.clone();
+        let goal_tree = goal_tree.clone();
+        let current_level_name = current_level_name.clone();
+        let onclick = Callback::from(move |_| {
+            state.set(
+                CTX.initial_state(Some(&current_level_name))
+                    .with_library(state.library)
+                    .with_level_order(state.level_order)
+                    .with_progress(&CTX, &progress),
+            );
+            goal_tree.set(match *goal_tree {
+                Some(_) => None,
+                None => Some(
+                    CTX.initial_goal_tree(Some(&current_level_name))
+                        .with_level_order(state.level_order)
+                        .with_progress(&CTX, &progress),
                 ),
-                None => (true, None),
+            });
+        });
+        html! {
+            <label>
+                <input type="checkbox" checked={goal_tree.is_some()} {onclick}/>
+                { "Goal-directed mode" }
+            </label>
+        }
+    };
+    let sandbox_panel = {
+        let oninput_goal = {
+            let sandbox_goal_text = sandbox_goal_text.clone();
+            Callback::from(move |e: InputEvent| {
+                sandbox_goal_text.set(e.target_unchecked_into::<HtmlInputElement>().value())
+            })
+        };
+        let oninput_before = {
+            let sandbox_before = sandbox_before.clone();
+            Callback::from(move |e: InputEvent| {
+                sandbox_before.set(e.target_unchecked_into::<HtmlInputElement>().value())
+            })
+        };
+        let onclick = {
+            let state = state.clone();
+            let goal_tree = goal_tree.clone();
+            let sandbox_goal_text = sandbox_goal_text.clone();
+            let sandbox_before = sandbox_before.clone();
+            let sandbox_error = sandbox_error.clone();
+            let level_addr = state.current_level_stmt_addr;
+            Callback::from(move |_| {
+                let before = match sandbox_before.trim() {
+                    "" => Some(level_addr),
+                    label => CTX.statement_addr(label),
+                };
+                let result = match before {
+                    Some(before) => CTX
+                        .sandbox_state(&sandbox_goal_text, before)
+                        .map_err(|err| {
+                            // Point at the offending token, counting characters
+                            let column = (*sandbox_goal_text)[..err.position].chars().count();
+                            let caret = " ".repeat(column);
+                            format!("{err}\n{}\n{caret}^", *sandbox_goal_text)
+                        }),
+                    None => Err(format!("Unknown label {}", *sandbox_before)),
+                };
+                match result {
+                    Ok(sandbox) => {
+                        state.set(sandbox);
+                        goal_tree.set(None);
+                        sandbox_error.set(None);
+                    }
+                    Err(err) => sandbox_error.set(Some(err)),
+                }
+            })
+        };
+        let error = (*sandbox_error)
+            .clone()
+            .map(|err| html! { <pre> { err } </pre> });
+        html! {
+            <details>
+                <summary> { "Sandbox" } </summary>
+                <label>
+                    { "Goal " }
+                    <input value={(*sandbox_goal_text).clone()} oninput={oninput_goal}/>
+                </label>
+                <label>
+                    { " Assertions before (default: this level) " }
+                    <input value={(*sandbox_before).clone()} oninput={oninput_before}/>
+                </label>
+                <button {onclick}>
+                    { "Start" }
+                </button>
+                { error }
+            </details>
+        }
+    };
+    let map_panel = {
+        const NODE_WIDTH: f64 = 100.0;
+        const NODE_HEIGHT: f64 = 24.0;
+        const COLUMN_WIDTH: f64 = 140.0;
+        const ROW_HEIGHT: f64 = 36.0;
+        let positions = level_map.grid_positions();
+        let num_columns = positions.iter().map(|&(column, _)| column + 1).max();
+        let num_rows = positions.iter().map(|&(_, row)| row + 1).max();
+        let width = num_columns.unwrap_or(0) as f64 * COLUMN_WIDTH;
+        let height = num_rows.unwrap_or(0) as f64 * ROW_HEIGHT;
+        let corner = |i: usize| {
+            let (column, row) = positions[i];
+            (column as f64 * COLUMN_WIDTH, row as f64 * ROW_HEIGHT)
+        };
+        let edges = level_map
+            .edges
+            .iter()
+            .map(|edge| {
+                let (x1, y1) = corner(edge.from);
+                let (x2, y2) = corner(edge.to);
+                html! {
+                    <line
+                        x1={(x1 + NODE_WIDTH).to_string()}
+                        y1={(y1 + NODE_HEIGHT / 2.0).to_string()}
+                        x2={x2.to_string()}
+                        y2={(y2 + NODE_HEIGHT / 2.0).to_string()}
+                        stroke="gray"
+                    />
+                }
+            })
+            .collect::<Html>();
+        let nodes = level_map
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let onclick = {
+                    let state = state.clone();
+                    let goal_tree = goal_tree.clone();
+                    let progress = progress.clone();
+                    let label = node.label.clone();
+                    Callback::from(move |_| {
+                        state.set(
+                            CTX.initial_state(Some(&label))
+                                .with_library(state.library)
+                                .with_level_order(state.level_order)
+                                .with_progress(&CTX, &progress),
+                        );
+                        goal_tree.set(None);
+                    })
+                };
+                let fill = match level_map.status(i) {
+                    NodeStatus::Solved => "palegreen",
+                    NodeStatus::Available => "lightyellow",
+                    NodeStatus::Locked => "lightgray",
+                };
+                let stroke_width = if node.addr == state.current_level_stmt_addr {
+                    "3"
+                } else {
+                    "1"
+                };
+                let (x, y) = corner(i);
+                html! {
+                    <g {onclick} style="cursor: pointer">
+                        <rect
+                            x={x.to_string()}
+                            y={y.to_string()}
+                            width={NODE_WIDTH.to_string()}
+                            height={NODE_HEIGHT.to_string()}
+                            rx="4"
+                            {fill}
+                            stroke="#444"
+                            stroke-width={stroke_width}
+                        />
+                        <text
+                            x={(x + NODE_WIDTH / 2.0).to_string()}
+                            y={(y + NODE_HEIGHT / 2.0).to_string()}
+                            text-anchor="middle"
+                            dominant-baseline="central"
+                            font-size="12"
+                        >
+                            { node.label.clone() }
+                        </text>
+                    </g>
+                }
+            })
+            .collect::<Html>();
+        let zoom_button = |text: &'static str, factor: f64| {
+            let map_zoom = map_zoom.clone();
+            let onclick =
+                Callback::from(move |_| map_zoom.set((*map_zoom * factor).clamp(0.25, 4.0)));
+            html! { <button {onclick}> { text } </button> }
+        };
+        html! {
+            <details>
+                <summary> { "Level map" } </summary>
+                { zoom_button("-", 0.8) }
+                { zoom_button("+", 1.25) }
+                <div style="overflow: auto; max-height: 300px">
+                    <svg
+                        width={(width * *map_zoom).to_string()}
+                        height={(height * *map_zoom).to_string()}
+                        viewBox={format!("0 0 {width} {height}")}
+                    >
+                        { edges }
+                        { nodes }
+                    </svg>
+                </div>
+            </details>
+        }
+    };
+    let start_level = {
+        let state = state.clone();
+        let goal_tree = goal_tree.clone();
+        let progress = progress.clone();
+        Callback::from(move |label: String| {
+            state.set(
+                CTX.initial_state(Some(&label))
+                    .with_library(state.library)
+                    .with_level_order(state.level_order)
+                    .with_progress(&CTX, &progress),
+            );
+            goal_tree.set(None);
+        })
+    };
+    let description = state.sandbox_goal.is_none().then(|| {
+        html! {
+            <p class="description">
+                { description_html((*level_description).clone(), &start_level) }
+            </p>
+        }
+    });
+    let level_header = match state.sandbox_goal {
+        Some(_) => {
+            let proved = state
+                .level_finished(&CTX)
+                .then(|| html! { <b> { " Proved!" } </b> });
+            html! {
+                <>
+                    { "Sandbox before " }
+                    { current_level_name }
+                    <br/>
+                    { string_to_html(state.render_goal(&CTX)) }
+                    { proved }
+                </>
+            }
+        }
+        None => html! {
+            <>
+                { "Level" }
+                { " " }
+                { current_level_name }
+                { format!(" (difficulty {})", level_difficulty.score()) }
+                <br/>
+                { string_to_html((*level_inference).clone()) }
+            </>
+        },
+    };
+    let library_panel = {
+        let radios = Library::ALL
+            .into_iter()
+            .map(|library| {
+                let onclick = {
+                    let state = state.clone();
+                    Callback::from(move |_| state.set(state.with_library(library)))
+                };
+                html! {
+                    <label>
+                        <input type="radio" checked={state.library == library} {onclick}/>
+                        { library.name() }
+                    </label>
+                }
+            })
+            .collect::<Html>();
+        let order_radios = LevelOrder::ALL
+            .into_iter()
+            .map(|level_order| {
+                let onclick = {
+                    let state = state.clone();
+                    let goal_tree = goal_tree.clone();
+                    Callback::from(move |_| {
+                        state.set(state.with_level_order(level_order));
+                        if let Some(tree) = &*goal_tree {
+                            goal_tree.set(Some(tree.with_level_order(level_order)));
+                        }
+                    })
+                };
+                html! {
+                    <label>
+                        <input type="radio" checked={state.level_order == level_order} {onclick}/>
+                        { level_order.name() }
+                    </label>
+                }
+            })
+            .collect::<Html>();
+        let locked = locked_deps
+            .iter()
+            .map(|&addr| {
+                let label = CTX.label(addr);
+                let onclick = {
+                    let state = state.clone();
+                    let goal_tree = goal_tree.clone();
+                    let progress = progress.clone();
+                    let label = label.clone();
+                    Callback::from(move |_| {
+                        state.set(
+                            CTX.initial_state(Some(&label))
+                                .with_library(state.library)
+                                .with_level_order(state.level_order)
+                                .with_progress(&CTX, &progress),
+                        );
+                        goal_tree.set(None);
+                    })
+                };
+                html! {
+                    <button {onclick}>
+                        { label }
+                    </button>
+                }
+            })
+            .collect::<Vec<_>>();
+        let locked = (!locked.is_empty()).then(|| {
+            html! {
+                <p>
+                    { "The reference proof uses theorems you haven't proven yet: " }
+                    { locked }
+                </p>
+            }
+        });
+        let search = (state.library != Library::Reference).then(|| {
+            let oninput = {
+                let library_search = library_search.clone();
+                Callback::from(move |e: InputEvent| {
+                    library_search.set(e.target_unchecked_into::<HtmlInputElement>().value())
+                })
+            };
+            let onclick_unifiable = {
+                let library_unifiable = library_unifiable.clone();
+                Callback::from(move |_| library_unifiable.set(!*library_unifiable))
             };
             html! {
-                <button class="dep-btn" {disabled} {onclick}>
-                    { text }
-                </button>
+                <>
+                    <label>
+                        { " Search " }
+                        <input value={(*library_search).clone()} {oninput}/>
+                    </label>
+                    <label>
+                        <input type="checkbox" checked={*library_unifiable} onclick={onclick_unifiable}/>
+                        { "Unifies with the goal or a stack entry" }
+                    </label>
+                </>
+            }
+        });
+        html! {
+            <>
+                { radios }
+                <br/>
+                { order_radios }
+                { search }
+                { locked }
+            </>
+        }
+    };
+    let mut button_texts = button_texts.borrow_mut();
+    let deps = match &*goal_tree_buttons {
+        Some(buttons) => {
+            let goal_tree = goal_tree.clone();
+            dep_buttons(
+                &lemmas,
+                &mut button_texts,
+                buttons.clone(),
+                level_finished,
+                move |step| {
+                    let next = (*goal_tree)
+                        .as_ref()
+                        .and_then(|tree| tree.apply(&CTX, step));
+                    goal_tree.set(next)
+                },
+            )
+        }
+        None => {
+            let state = state.clone();
+            let recording = recording.clone();
+            let push_lemmas = lemmas.clone();
+            let mut button_cache = button_cache.borrow_mut();
+            let buttons = match state.library {
+                Library::Reference => button_cache.buttons(&CTX, &lemmas, &state),
+                Library::Open | Library::Unlocked => button_cache.search_library(
+                    &CTX,
+                    &lemmas,
+                    &state,
+                    &library_search,
+                    *library_unifiable,
+                ),
             }
+            .to_vec();
+            dep_buttons(
+                &lemmas,
+                &mut button_texts,
+                buttons,
+                level_finished,
+                move |step| {
+                    let action = Action::Push(step);
+                    let Some(next_state) = state.apply(&CTX, &push_lemmas, action) else {
+                        return;
+                    };
+                    if let Some(mac) = &*recording {
+                        let mut mac = mac.clone();
+                        mac.record(&CTX, &push_lemmas, &state, action);
+                        recording.set(Some(mac));
+                    }
+                    state.set(next_state)
+                },
+            )
+        }
+    };
+    let goals = (*goal_tree).as_ref().map(|tree| {
+        tree.render_goals(&CTX)
+            .into_iter()
+            .enumerate()
+            .map(|(i, expr)| {
+                let expr =
+                    string_to_html(format!("<div style='display: inline-block'> {expr} </div>"));
+                let onclick = tree.select(i).map(|next_tree| {
+                    let goal_tree = goal_tree.clone();
+                    Callback::from(move |_| goal_tree.set(Some(next_tree.clone())))
+                });
+                html! {
+                    <li>
+                        <input type="radio" checked={tree.selected == i} {onclick}/>
+                        { " " }
+                        { expr }
+                    </li>
+                }
+            })
+            .collect::<Html>()
+    });
+    let oninput_lemma_name = {
+        let lemma_name = lemma_name.clone();
+        Callback::from(move |e: InputEvent| {
+            lemma_name.set(e.target_unchecked_into::<HtmlInputElement>().value())
         })
-        .collect::<Html>();
+    };
+    let macro_panel = {
+        let recorder = match &*recording {
+            Some(mac) => {
+                let oninput = {
+                    let recording = recording.clone();
+                    Callback::from(move |e: InputEvent| {
+                        let mut mac = (*recording).clone().unwrap_or_default();
+                        mac.name = e.target_unchecked_into::<HtmlInputElement>().value();
+                        recording.set(Some(mac));
+                    })
+                };
+                let onclick_save = (!mac.name.is_empty()).then(|| {
+                    let recording = recording.clone();
+                    let progress_storage = progress_storage.clone();
+                    let macro_args = macro_args.clone();
+                    let mut progress = progress.clone();
+                    progress.macros.push(mac.clone());
+                    Callback::from(move |_| {
+                        progress_storage.set(progress.clone());
+                        macro_args.set(Vec::new());
+                        recording.set(None);
+                    })
+                });
+                let onclick_cancel = {
+                    let recording = recording.clone();
+                    Callback::from(move |_| recording.set(None))
+                };
+                html! {
+                    <>
+                        <label>
+                            { "Macro name " }
+                            <input value={mac.name.clone()} {oninput}/>
+                        </label>
+                        <button disabled={onclick_save.is_none()} onclick={onclick_save}>
+                            { "Save macro" }
+                        </button>
+                        <button onclick={onclick_cancel}>
+                            { "Cancel" }
+                        </button>
+                    </>
+                }
+            }
+            None => {
+                let recording = recording.clone();
+                let onclick = Callback::from(move |_| recording.set(Some(Macro::new(""))));
+                html! {
+                    <button {onclick}>
+                        { "Record macro" }
+                    </button>
+                }
+            }
+        };
+        let macros = progress
+            .macros
+            .iter()
+            .enumerate()
+            .map(|(i, mac)| {
+                let args = macro_args
+                    .get(i)
+                    .cloned()
+                    .unwrap_or_else(|| mac.params.join(" "));
+                let onclick = macro_states[i].clone().map(|next_state| {
+                    let state = state.clone();
+                    Callback::from(move |_| state.set(next_state.clone()))
+                });
+                let oninput = {
+                    let macro_args = macro_args.clone();
+                    let defaults = progress
+                        .macros
+                        .iter()
+                        .map(|mac| mac.params.join(" "))
+                        .collect::<Vec<_>>();
+                    Callback::from(move |e: InputEvent| {
+                        let mut args = (*macro_args).clone();
+                        args.extend(defaults[args.len().min(defaults.len())..].iter().cloned());
+                        args[i] = e.target_unchecked_into::<HtmlInputElement>().value();
+                        macro_args.set(args);
+                    })
+                };
+                html! {
+                    <li>
+                        <button disabled={onclick.is_none()} {onclick}>
+                            { mac.name.clone() }
+                        </button>
+                        { " " }
+                        <input value={args} {oninput}/>
+                    </li>
+                }
+            })
+            .collect::<Html>();
+        html! {
+            <>
+                { recorder }
+                <ul> { macros } </ul>
+            </>
+        }
+    };
+    let tactic_panel = {
+        let tactic_buttons = Tactic::ALL
+            .into_iter()
+            .map(|tactic| {
+                let onclick = {
+                    let state = state.clone();
+                    let lemmas = lemmas.clone();
+                    let tactic_candidates = tactic_candidates.clone();
+                    Callback::from(move |_| {
+                        tactic_candidates.set(state.tactic(&CTX, &lemmas, tactic))
+                    })
+                };
+                html! {
+                    <button {onclick}>
+                        { tactic.name() }
+                    </button>
+                }
+            })
+            .collect::<Html>();
+        let prove_button = {
+            let state = state.clone();
+            let lemmas = lemmas.clone();
+            let tactic_candidates = tactic_candidates.clone();
+            let prover_budget = *prover_budget;
+            let onclick = Callback::from(move |_| {
+                let actions = state.prove_prop(&CTX, &lemmas, prover_budget);
+                tactic_candidates.set(actions.into_iter().collect())
+            });
+            html! {
+                <button {onclick}>
+                    { "Prove" }
+                </button>
+            }
+        };
+        let budget_input = {
+            let prover_budget = prover_budget.clone();
+            let oninput = Callback::from(move |e: InputEvent| {
+                let value = e.target_unchecked_into::<HtmlInputElement>().value();
+                if let Ok(budget) = value.parse() {
+                    prover_budget.set(budget);
+                }
+            });
+            html! {
+                <input type="number" min="1" max="100000" value={prover_budget.to_string()} {oninput}/>
+            }
+        };
+        let candidates = tactic_candidates
+            .iter()
+            .filter_map(|actions| {
+                // Candidates from an earlier state may no longer apply
+                let preview = state.apply_all(&CTX, &lemmas, actions)?;
+                let top = preview.render_stack(&CTX).pop()?;
+                let top =
+                    string_to_html(format!("<div style='display: inline-block'> {top} </div>"));
+                let onclick = actions_callback(&state, &recording, &lemmas, actions);
+                Some(html! {
+                    <li>
+                        <button {onclick}>
+                            { "Apply" }
+                        </button>
+                        { " " }
+                        { top }
+                    </li>
+                })
+            })
+            .collect::<Html>();
+        html! {
+            <>
+                { tactic_buttons }
+                { prove_button }
+                { budget_input }
+                <ul> { candidates } </ul>
+            </>
+        }
+    };
     let stack = state
         .render_stack(&CTX)
         .into_iter()
@@ -89,28 +972,66 @@ pub fn app() -> Html {
             let expr = string_to_html(format!("<div style='display: inline-block'> {expr} </div>"));
 
             let [up_button, down_button, delete_button, copy_button] = [
-                ("↑", state.stack_swap(i, i - 1)),
-                ("↓", state.stack_swap(i, i + 1)),
-                ("🗑️", state.stack_delete(i)),
-                ("⿻", state.stack_copy(i)),
+                ("↑", Action::Swap(i, i.wrapping_sub(1))),
+                ("↓", Action::Swap(i, i + 1)),
+                ("🗑️", Action::Delete(i)),
+                ("⿻", Action::Copy(i)),
             ]
-            .map(|(text, next_state)| {
-                let (disabled, onclick) = match next_state {
-                    Some(next_state) => {
-                        let state = state.clone();
+            .map(|(text, action)| {
+                let onclick = actions_callback(&state, &recording, &lemmas, &[action]);
+                let disabled = onclick.is_none();
+                html! {
+                    <button disabled={disabled} onclick={onclick}>
+                        {text}
+                    </button>
+                }
+            });
+
+            let truth_table_button = {
+                let truth_table_entry = truth_table_entry.clone();
+                let onclick = Callback::from(move |_| truth_table_entry.set(Some(i)));
+                html! {
+                    <button {onclick}>
+                        { "TT" }
+                    </button>
+                }
+            };
+
+            let search_button = {
+                let state = state.clone();
+                let search_results = search_results.clone();
+                let onclick =
+                    Callback::from(move |_| search_results.set(state.search_uses(&CTX, i)));
+                html! {
+                    <button title="What can I do with this?" {onclick}>
+                        { "?" }
+                    </button>
+                }
+            };
+
+            let lemma_button = {
+                let (disabled, onclick) = match entry_lemmas[i].clone() {
+                    Some(lemma) => {
+                        let progress_storage = progress_storage.clone();
+                        let lemma_name = lemma_name.clone();
+                        let mut progress = progress.clone();
+                        progress.lemmas.push(CTX.save_lemma(&lemma, &lemmas));
                         (
                             false,
-                            Some(Callback::from(move |_| state.set(next_state.clone()))),
+                            Some(Callback::from(move |_| {
+                                progress_storage.set(progress.clone());
+                                lemma_name.set(String::new());
+                            })),
                         )
                     }
                     None => (true, None),
                 };
                 html! {
-                    <button disabled={disabled} onclick={onclick}>
-                        {text}
+                    <button {disabled} {onclick}>
+                        { "Save as lemma" }
                     </button>
                 }
-            });
+            };
 
             html! {
                 <li>
@@ -118,28 +1039,111 @@ pub fn app() -> Html {
                     { down_button }
                     { delete_button }
                     { copy_button }
+                    { truth_table_button }
+                    { search_button }
+                    { lemma_button }
                     { " " }
                     { expr }
                 </li>
             }
         })
         .collect::<Html>();
+    let truth_tables = {
+        let goal_table = truth_table_html(CTX.truth_table(&state.goal(&CTX)));
+        let entry_table = truth_table_entry
+            .and_then(|i| state.proof_stack.get(i))
+            .map(|entry| {
+                html! {
+                    <>
+                        <h4> { "Stack entry" } </h4>
+                        { truth_table_html(CTX.truth_table(&entry.expr)) }
+                    </>
+                }
+            });
+        html! {
+            <details>
+                <summary> { "Truth table" } </summary>
+                <h4> { "Level goal" } </h4>
+                { goal_table }
+                { entry_table }
+            </details>
+        }
+    };
+    let search_panel = {
+        let onclick_producers = {
+            let state = state.clone();
+            let search_results = search_results.clone();
+            Callback::from(move |_| search_results.set(state.search_producers(&CTX)))
+        };
+        let onclick_clear = {
+            let search_results = search_results.clone();
+            Callback::from(move |_| search_results.set(Vec::new()))
+        };
+        let results = search_results
+            .iter()
+            .map(|result| {
+                let part = match result.matched {
+                    Matched::Conclusion => String::from("conclusion"),
+                    Matched::Hyp(i) => format!("hypothesis {}", i + 1),
+                };
+                let substs = result
+                    .substs
+                    .iter()
+                    .map(|(var, value)| format!("{var} := {value}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                html! {
+                    <li>
+                        { format!("{} ({part}) ", CTX.label(result.addr)) }
+                        { string_to_html(substs) }
+                    </li>
+                }
+            })
+            .collect::<Html>();
+        html! {
+            <>
+                <button onclick={onclick_producers}>
+                    { "What could produce the goal?" }
+                </button>
+                <button onclick={onclick_clear}>
+                    { "Clear search" }
+                </button>
+                <ul> { results } </ul>
+            </>
+        }
+    };
     html! {
         <main>
             <h1> { "Proof stack game" } </h1>
 
             <hr/>
 
+            { mode_checkbox }
+            { library_panel }
+
+            <hr/>
+
+            { sandbox_panel }
+            { map_panel }
+
+            <hr/>
+
             <h2>
-                { "Level" }
-                { " " }
-                { current_level_name }
-                <br/>
-                { string_to_html(render_inference(state.current_level_stmt_addr)) }
+                { level_header }
                 { " " }
                 { next_level_button }
             </h2>
 
+            { description }
+
+            if let Some(proof) = &*exported_proof {
+                <pre class="proof">{ proof.clone() }</pre>
+            }
+
+            { truth_tables }
+
+            { search_panel }
+
             <hr/>
 
             // Steps to pop onto the stack
@@ -149,10 +1153,24 @@ pub fn app() -> Html {
 
             <hr/>
 
-            // Proof stack
-            <ul>
-                { stack }
-            </ul>
+            {
+                match goals {
+                    // Open goals
+                    Some(goals) => html! { <ul> { goals } </ul> },
+                    // Proof stack
+                    None => html! {
+                        <>
+                            { macro_panel }
+                            { tactic_panel }
+                            <label>
+                                { "Lemma name " }
+                                <input value={(*lemma_name).clone()} oninput={oninput_lemma_name}/>
+                            </label>
+                            <ul> { stack } </ul>
+                        </>
+                    },
+                }
+            }
         </main>
     }
 }